@@ -0,0 +1,437 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use futures::StreamExt;
+
+use crate::{
+    cache::Cache,
+    normalize,
+    site::{self, Registry, Resolution, SpoilerSite},
+    slug, Error, Spoiler,
+};
+
+/// One site's version of a card.
+#[derive(Debug)]
+pub struct Sighting {
+    pub site: &'static str,
+    pub spoiler: Spoiler,
+    /// What came of resolving the spoiler on its site.
+    pub resolution: Resolution,
+}
+
+impl Sighting {
+    fn new(site: &'static str, spoiler: Spoiler) -> Self {
+        Self {
+            site,
+            spoiler,
+            resolution: Resolution::Resolved,
+        }
+    }
+}
+
+/// A card as reported by every site that spoiled it.
+#[derive(Debug)]
+pub struct Merged {
+    /// The first site's spoiler, with whatever it was missing filled in from the other sites.
+    pub spoiler: Spoiler,
+    /// Every site's version of the card, in the order the sites were given to the aggregator.
+    pub sightings: Vec<Sighting>,
+}
+
+impl Merged {
+    fn new(site: &'static str, spoiler: Spoiler) -> Self {
+        Self {
+            spoiler: spoiler.clone(),
+            sightings: vec![Sighting::new(site, spoiler)],
+        }
+    }
+
+    fn merge(&mut self) {
+        let [first, rest @ ..] = &self.sightings[..] else {
+            return;
+        };
+        let mut spoiler = first.spoiler.clone();
+        for other in rest.iter().map(|s| &s.spoiler) {
+            spoiler.name = spoiler.name.or_else(|| other.name.clone());
+            spoiler.source = spoiler.source.or_else(|| other.source.clone());
+        }
+        self.spoiler = spoiler;
+    }
+}
+
+/// What identifies a card across sites.
+///
+/// Sites don't agree on urls or image names, so a card is identified by its name, or the slug
+/// of its page when the name isn't known yet, with everything but letters and digits removed.
+/// Sites that expose the set code must agree on it too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Identity {
+    set: Option<String>,
+    name: String,
+}
+
+impl Identity {
+    fn of(spoiler: &Spoiler) -> Option<Self> {
+        let name = normalize(spoiler.name.as_deref().or(slug(&spoiler.source_site_url))?);
+        (!name.is_empty()).then(|| Self {
+            set: spoiler.set_code.as_deref().map(str::to_lowercase),
+            name,
+        })
+    }
+
+    fn same_card(&self, other: &Self) -> bool {
+        self.name == other.name
+            && match (&self.set, &other.set) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            }
+    }
+}
+
+/// Fetches spoilers from several sites at once and reports each card only once.
+#[derive(Clone)]
+pub struct Aggregator {
+    sites: Vec<Arc<dyn SpoilerSite>>,
+}
+
+impl Aggregator {
+    pub fn new<I: IntoIterator<Item = Arc<dyn SpoilerSite>>>(sites: I) -> Self {
+        Self {
+            sites: sites.into_iter().collect(),
+        }
+    }
+
+    /// Fetches the cards none of the sites' spoilers were seen by `cache` before, oldest first,
+    /// and marks them as seen, those a site failed to resolve too.
+    ///
+    /// A site that fails is logged and skipped, this only fails if every site does.
+    #[tracing::instrument(skip_all)]
+    pub async fn new_cards<C: Cache + Send + 'static>(
+        &self,
+        mut cache: C,
+    ) -> Result<Vec<Merged>, Error> {
+        let candidates = self.candidates(&mut cache).await?;
+        self.acknowledge(&mut cache, &candidates).await?;
+        Ok(candidates.into_iter().map(|c| c.merged).collect())
+    }
+
+    /// Like [`new_cards`](Self::new_cards), but the cards are only marked as seen once the
+    /// caller [acknowledges](Self::acknowledge) them.
+    ///
+    /// A card that was reported before isn't a candidate, even if a site lists it for the first
+    /// time. Its new sightings are staged in `cache` right away, so that the site doesn't report
+    /// it on its own later, and committed along with the acknowledged cards.
+    #[tracing::instrument(skip_all)]
+    pub async fn candidates<C: Cache>(&self, cache: &mut C) -> Result<Vec<Candidate>, Error> {
+        tracing::trace!(sites = self.sites.len(), "fetching listings");
+        let listings =
+            futures::future::join_all(self.sites.iter().map(|s| s.fetch_listing())).await;
+
+        let mut failed = 0;
+        let mut error = None;
+        let mut merged = Vec::<Merged>::new();
+        let mut by_name = HashMap::<String, Vec<(Identity, usize)>>::new();
+        for (site, listing) in self.sites.iter().zip(listings) {
+            let listing = match listing {
+                Ok(listing) => listing,
+                Err(e) => {
+                    tracing::warn!(site = site.name(), ?e, "failed to fetch listing");
+                    failed += 1;
+                    error.get_or_insert(e);
+                    continue;
+                }
+            };
+            for spoiler in listing {
+                let Some(identity) = Identity::of(&spoiler) else {
+                    merged.push(Merged::new(site.name(), spoiler));
+                    continue;
+                };
+                let candidates = by_name.entry(identity.name.clone()).or_default();
+                match candidates.iter().find(|(i, _)| i.same_card(&identity)) {
+                    Some(&(_, i)) => merged[i]
+                        .sightings
+                        .push(Sighting::new(site.name(), spoiler)),
+                    None => {
+                        candidates.push((identity, merged.len()));
+                        merged.push(Merged::new(site.name(), spoiler));
+                    }
+                }
+            }
+        }
+        if let (Some(e), true) = (error, failed == self.sites.len()) {
+            return Err(e);
+        }
+
+        let spoilers = merged
+            .iter()
+            .flat_map(|m| m.sightings.iter().map(|s| s.spoiler.clone()))
+            .collect::<Vec<_>>();
+        let new = cache.filter_new(&spoilers).await?;
+        // sites can agree on keys, their pages tell the sightings apart
+        let new = new
+            .into_iter()
+            .map(|s| s.source_site_url)
+            .collect::<HashSet<_>>();
+        let (mut merged, reported): (Vec<_>, Vec<_>) = merged.into_iter().partition(|m| {
+            m.sightings
+                .iter()
+                .all(|s| new.contains(&s.spoiler.source_site_url))
+        });
+        let sightings = reported
+            .iter()
+            .flat_map(|m| &m.sightings)
+            .filter(|s| new.contains(&s.spoiler.source_site_url))
+            .map(|s| s.spoiler.clone())
+            .collect::<Vec<_>>();
+        cache.mark_seen(&sightings).await?;
+        merged.reverse();
+
+        tracing::trace!(count = merged.len(), "resolving spoilers");
+        let mut candidates = merged
+            .into_iter()
+            .map(|merged| Candidate {
+                listed: merged.sightings.iter().map(|s| s.spoiler.clone()).collect(),
+                merged,
+            })
+            .collect::<Vec<_>>();
+        futures::stream::iter(candidates.iter_mut().map(|c| &mut c.merged))
+            .for_each_concurrent(site::CONCURRENCY, |m| async {
+                for sighting in &mut m.sightings {
+                    if let Some(site) = self.sites.iter().find(|s| s.name() == sighting.site) {
+                        sighting.resolution = site.resolve(&mut sighting.spoiler).await;
+                    }
+                }
+                m.merge();
+            })
+            .await;
+        Ok(candidates)
+    }
+
+    /// Marks every sighting of the `delivered` cards as seen in `cache`, and commits it.
+    pub async fn acknowledge<'c, C: Cache>(
+        &self,
+        cache: &mut C,
+        delivered: impl IntoIterator<Item = &'c Candidate>,
+    ) -> Result<(), Error> {
+        let spoilers = delivered
+            .into_iter()
+            .flat_map(|c| c.listed.iter().cloned())
+            .collect::<Vec<_>>();
+        tracing::trace!(count = spoilers.len(), "committing cache");
+        cache.mark_seen(&spoilers).await?;
+        cache.commit().await
+    }
+}
+
+/// A card from [`Aggregator::candidates`], that isn't seen until it's
+/// [acknowledged](Aggregator::acknowledge).
+#[derive(Debug)]
+pub struct Candidate {
+    pub merged: Merged,
+    /// Every sighting's spoiler as it was listed, before resolving changed it.
+    listed: Vec<Spoiler>,
+}
+
+impl From<&Registry> for Aggregator {
+    fn from(registry: &Registry) -> Self {
+        Self::new(registry.iter().cloned())
+    }
+}
+
+impl std::fmt::Debug for Aggregator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.sites.iter().map(|s| s.name()))
+            .finish()
+    }
+}
+
+#[allow(dead_code)]
+fn _assert() {
+    fn is_send<T: Send>(_: T) {}
+    let aggregator = Aggregator::from(&Registry::default());
+    is_send(aggregator.new_cards(super::cache::empty::Empty));
+}
+
+#[cfg(test)]
+mod test {
+    use reqwest::{StatusCode, Url};
+
+    use super::*;
+    use crate::{
+        cache::{empty::Empty, test::Images},
+        fixtures,
+        magic_spoiler::MagicSpoiler,
+        mythic::Mythic,
+        transport::{Backoff, Fake},
+    };
+
+    const MYTHIC_LISTING: &str = r#"
+        <div class="grid-card"><a href="woe/cards/gingerbreadhunter.html"><img src="woe/cards/gingerbreadhunter.jpg"></a></div>
+        <div class="grid-card"><a href="woe/cards/ashiokwickedmanipulator.html"><img src="woe/cards/ashiokwickedmanipulator.jpg"></a></div>
+    "#;
+
+    async fn aggregator() -> Aggregator {
+        let url = |s: &str| Url::parse(s).unwrap();
+        let mythic = Fake::new()
+            .with_page(
+                url("https://mythicspoiler.com/newspoilers.html"),
+                MYTHIC_LISTING,
+            )
+            .with_page(
+                url("https://mythicspoiler.com/woe/cards/gingerbreadhunter.html"),
+                fixtures::handwritten("mythic/woe/gingerbreadhunter.html").await,
+            );
+        let magic_spoiler = Fake::new().with_page(
+            url("https://www.magicspoiler.com/mtg-spoiler/"),
+            fixtures::handwritten("magic_spoiler/newspoilers.html").await,
+        );
+        Aggregator::new([
+            Arc::new(Mythic::new(mythic)) as Arc<dyn SpoilerSite>,
+            Arc::new(MagicSpoiler::new(magic_spoiler)),
+        ])
+    }
+
+    #[tokio::test]
+    async fn same_card_from_two_sites_is_reported_once() {
+        let cards = aggregator().await.new_cards(Empty).await.unwrap();
+        let names = cards
+            .iter()
+            .map(|c| c.spoiler.name.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                Some("Vraska, Betrayal's Sting"),
+                Some("Picklock Prankster"),
+                Some("Raging Firebolt"),
+                None,
+                Some("Gingerbread Hunter"),
+            ]
+        );
+
+        let gingerbread = cards.last().unwrap();
+        assert_eq!(
+            gingerbread
+                .sightings
+                .iter()
+                .map(|s| s.site)
+                .collect::<Vec<_>>(),
+            ["mythic", "magic-spoiler"]
+        );
+        assert_eq!(
+            gingerbread.spoiler.image.as_str(),
+            "https://mythicspoiler.com/woe/cards/gingerbreadhunter.jpg"
+        );
+        assert_eq!(
+            gingerbread.spoiler.source.as_ref().map(|s| &s.name[..]),
+            Some("Wizards of the Coast")
+        );
+    }
+
+    #[tokio::test]
+    async fn a_card_seen_on_any_site_is_not_new() {
+        let cache = Images::new(["https://mythicspoiler.com/woe/cards/gingerbreadhunter.jpg"]);
+        let cards = aggregator().await.new_cards(cache.clone()).await.unwrap();
+        assert_eq!(cards.len(), 4);
+        assert!(cards
+            .iter()
+            .all(|c| c.spoiler.name.as_deref() != Some("Gingerbread Hunter")));
+        // nor is it once the other site stops listing it
+        assert!(cache.contains(
+            "https://www.magicspoiler.com/wp-content/uploads/2023/08/gingerbread-hunter.jpg"
+        ));
+    }
+
+    #[tokio::test]
+    async fn cards_are_seen_once_acknowledged() {
+        let aggregator = aggregator().await;
+        let mut cache = Images::default();
+        let candidates = aggregator.candidates(&mut cache).await.unwrap();
+        assert_eq!(candidates.len(), 5);
+        let hunter = candidates.last().unwrap();
+        aggregator.acknowledge(&mut cache, [hunter]).await.unwrap();
+        // every sighting of it
+        assert!(cache.contains("https://mythicspoiler.com/woe/cards/gingerbreadhunter.jpg"));
+        assert!(cache.contains(
+            "https://www.magicspoiler.com/wp-content/uploads/2023/08/gingerbread-hunter.jpg"
+        ));
+
+        let candidates = aggregator.candidates(&mut cache).await.unwrap();
+        assert_eq!(candidates.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn cards_that_failed_to_resolve_are_reported_once() {
+        let listing = Url::parse("https://mythicspoiler.com/newspoilers.html").unwrap();
+        let mythic = Fake::new()
+            .with_page(listing, MYTHIC_LISTING)
+            .with_response(
+                Url::parse("https://mythicspoiler.com/woe/cards/gingerbreadhunter.html").unwrap(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "",
+            );
+        let aggregator = Aggregator::new([
+            Arc::new(Mythic::new(mythic).backoff(Backoff::none())) as Arc<dyn SpoilerSite>
+        ]);
+        let cache = Images::default();
+        let first = aggregator.new_cards(cache.clone()).await.unwrap();
+        assert_eq!(first.len(), 2);
+        assert!(first
+            .iter()
+            .any(|m| matches!(m.sightings[0].resolution, Resolution::Failed(_))));
+        assert!(aggregator.new_cards(cache).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_fails_if_every_site_does() {
+        let url = |s: &str| Url::parse(s).unwrap();
+        let empty = Fake::new().with_page(url("https://mythicspoiler.com/newspoilers.html"), "");
+        let aggregator = Aggregator::new([
+            Arc::new(Mythic::new(empty)) as Arc<dyn SpoilerSite>,
+            Arc::new(MagicSpoiler::new(Fake::new())),
+        ]);
+        assert!(aggregator.new_cards(Empty).await.unwrap().is_empty());
+
+        let aggregator = Aggregator::new([
+            Arc::new(Mythic::new(Fake::new())) as Arc<dyn SpoilerSite>,
+            Arc::new(MagicSpoiler::new(Fake::new())),
+        ]);
+        assert!(aggregator.new_cards(Empty).await.is_err());
+    }
+
+    #[test]
+    fn identity_ignores_punctuation_and_agrees_on_set() {
+        let spoiler = |url: &str, name: Option<&str>, set: Option<&str>| Spoiler {
+            name: name.map(Into::into),
+            source_site_url: Url::parse(url).unwrap(),
+            image: Url::parse(url).unwrap(),
+            source: None,
+            set_code: set.map(Into::into),
+        };
+        let mythic = Identity::of(&spoiler(
+            "https://mythicspoiler.com/one/cards/vraskabetrayalssting.html",
+            None,
+            Some("one"),
+        ))
+        .unwrap();
+        let magic_spoiler = Identity::of(&spoiler(
+            "https://www.magicspoiler.com/mtg-spoiler/vraska-betrayals-sting/",
+            Some("Vraska, Betrayal's Sting"),
+            None,
+        ))
+        .unwrap();
+        assert_eq!(mythic.set.as_deref(), Some("one"));
+        assert!(mythic.same_card(&magic_spoiler));
+
+        let reprint = Identity::of(&spoiler(
+            "https://mythicspoiler.com/mom/cards/vraskabetrayalssting.html",
+            None,
+            Some("mom"),
+        ))
+        .unwrap();
+        assert!(!mythic.same_card(&reprint));
+    }
+}
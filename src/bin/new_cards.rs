@@ -1,3 +1,8 @@
+use mtg_spoilers::{
+    aggregate::Aggregator,
+    config::Config,
+    site::{self, Registry, Resolution},
+};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
 #[tokio::main]
@@ -10,10 +15,40 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     //     .await
     //     .unwrap();
     let cache = mtg_spoilers::cache::empty::Empty;
-    let new_cards = match std::env::args().nth(1).as_deref() {
-        Some("magic-spoiler") => mtg_spoilers::magic_spoiler::new_cards(cache).await?,
-        Some("mythic") | None => mtg_spoilers::mythic::new_cards(cache).await?,
-        Some(s) => return Err(format!("invalid source: {s:?}").into()),
+    // scraping rules can be overridden without a new release, see `mtg_spoilers::config::Config`
+    let registry = match std::env::var_os("MTG_SPOILERS_CONFIG") {
+        Some(path) => Config::load(path).await?.registry()?,
+        None => Registry::default(),
+    };
+    let name = std::env::args().nth(1);
+    let name = name.as_deref().unwrap_or("mythic");
+    if name == "all" {
+        let new_cards = Aggregator::from(&registry).new_cards(cache).await?;
+        new_cards
+            .iter()
+            .skip(new_cards.len().saturating_sub(10))
+            .for_each(|p| println!("{p:?}"));
+        return Ok(());
+    }
+    let Some(source) = registry.get(name) else {
+        return Err(format!(
+            "invalid source: {name:?}, expected \"all\" or one of {:?}",
+            registry.names().collect::<Vec<_>>()
+        )
+        .into());
+    };
+    let new_cards = match std::env::args().nth(2) {
+        Some(set) => source.fetch_set(&set).await?,
+        None => site::new_cards(source.as_ref(), cache)
+            .await?
+            .into_iter()
+            .map(|resolved| {
+                if let Resolution::Failed(e) = &resolved.resolution {
+                    eprintln!("couldn't resolve {}: {e}", resolved.spoiler.source_site_url);
+                }
+                resolved.spoiler
+            })
+            .collect(),
     };
 
     new_cards
@@ -1,17 +1,304 @@
 pub mod empty;
 pub mod file;
+#[cfg(feature = "redis")]
+pub mod redis;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 
-use std::{future::Future, io};
+use std::future::Future;
 
-use super::Spoiler;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
 
-pub trait Cache {
-    fn is_new(&mut self, spoiler: &Spoiler) -> bool;
+use super::{canonical, Card, Error, Spoiler};
 
-    fn persist(self) -> impl Future<Output = io::Result<()>> + Send
+/// What a cache remembers of a card, to tell what changed about it the next time it's listed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Known {
+    /// The spoiler as it was last resolved.
+    pub spoiler: Spoiler,
+    /// The card's text, if it was ever fetched.
+    pub card: Option<Card>,
+}
+
+impl Known {
+    /// Whether there's nothing left to learn about the card: its name, and its text if
+    /// `with_text`.
+    pub fn is_complete(&self, with_text: bool) -> bool {
+        self.spoiler.name.is_some()
+            && (!with_text || self.card.as_ref().is_some_and(Card::has_text))
+    }
+
+    /// What `now` says that this didn't, if anything.
+    pub fn changes(&self, now: &Known) -> Option<Changes> {
+        let has_text = |k: &Known| k.card.as_ref().is_some_and(Card::has_text);
+        let changes = Changes {
+            previous_image: (canonical(&self.spoiler.image) != canonical(&now.spoiler.image))
+                .then(|| self.spoiler.image.clone()),
+            name: self.spoiler.name.is_none() && now.spoiler.name.is_some(),
+            text: !has_text(self) && has_text(now),
+        };
+        (changes != Changes::default()).then_some(changes)
+    }
+
+    /// This, with whatever `previous` knew that this doesn't, e.g. because the card's page
+    /// didn't load this time.
+    pub(crate) fn or(mut self, previous: Known) -> Known {
+        self.spoiler.name = self.spoiler.name.or(previous.spoiler.name);
+        if !self.card.as_ref().is_some_and(Card::has_text) {
+            self.card = previous.card.or(self.card);
+        }
+        self
+    }
+}
+
+/// How a spoiler compares to what a cache knew of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Seen {
+    New,
+    /// Seen before, and it's been filled in or given a new image since.
+    Updated(Changes),
+    Unchanged,
+}
+
+/// What's new about a card that was seen before.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Changes {
+    /// The image it had, if it was replaced.
+    pub previous_image: Option<Url>,
+    /// Whether its name wasn't known before.
+    pub name: bool,
+    /// Whether its text wasn't known before.
+    pub text: bool,
+}
+
+/// Remembers the cards that were seen, so that each is only reported once.
+///
+/// Changes are staged until they're [committed](Self::commit), so that a run that fails
+/// halfway can [roll back](Self::rollback) instead of marking cards it never delivered as seen.
+/// Caches that are dropped without committing forget what was staged.
+pub trait Cache: Send {
+    /// The spoilers of cards that weren't seen, in order.
+    fn filter_new(
+        &mut self,
+        spoilers: &[Spoiler],
+    ) -> impl Future<Output = Result<Vec<Spoiler>, Error>> + Send;
+
+    /// Stages the cards of `spoilers` as seen.
+    fn mark_seen(&mut self, spoilers: &[Spoiler])
+        -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Stages the cards of `spoilers` as never seen, so that they're reported again.
+    fn forget(&mut self, spoilers: &[Spoiler]) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// What's remembered of the card of each of `spoilers`, for caches that can tell how cards
+    /// change, see [`Seen::Updated`].
+    ///
+    /// Only asked about spoilers [`filter_new`](Self::filter_new) said weren't new.
+    fn known(
+        &mut self,
+        spoilers: &[Spoiler],
+    ) -> impl Future<Output = Result<Vec<Option<Known>>, Error>> + Send {
+        let unknown = vec![None; spoilers.len()];
+        async { Ok(unknown) }
+    }
+
+    /// Stages what was learned of cards by resolving them, for [`known`](Self::known) to
+    /// compare against next time, and marks them as seen.
+    fn remember(&mut self, known: &[Known]) -> impl Future<Output = Result<(), Error>> + Send {
+        let spoilers = known.iter().map(|k| k.spoiler.clone()).collect::<Vec<_>>();
+        async move { self.mark_seen(&spoilers).await }
+    }
+
+    /// Saves the staged changes.
+    ///
+    /// If this fails they stay staged, for another commit or a rollback.
+    fn commit(&mut self) -> impl Future<Output = Result<(), Error>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Drops the staged changes.
+    fn rollback(&mut self) -> impl Future<Output = Result<(), Error>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// What every cache is tested against, and a cache to test their users with.
+#[cfg(test)]
+pub(crate) mod test {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::SpoilerSource;
+
+    /// A card of Wilds of Eldraine, as mythicspoiler lists it.
+    pub(crate) fn spoiler(slug: &str) -> Spoiler {
+        let url = |ext: &str| {
+            Url::parse(&format!("https://mythicspoiler.com/woe/cards/{slug}.{ext}")).unwrap()
+        };
+        Spoiler {
+            name: None,
+            source_site_url: url("html"),
+            image: url("jpg"),
+            source: Some(SpoilerSource {
+                name: "WeeklyMTG".into(),
+                url: None,
+            }),
+            set_code: Some("woe".into()),
+        }
+    }
+
+    pub(crate) async fn is_new<C: Cache>(cache: &mut C, spoiler: &Spoiler) -> bool {
+        let new = cache.filter_new(std::slice::from_ref(spoiler)).await;
+        !new.unwrap().is_empty()
+    }
+
+    pub(crate) async fn known_of<C: Cache>(cache: &mut C, spoiler: &Spoiler) -> Option<Known> {
+        let known = cache.known(std::slice::from_ref(spoiler)).await;
+        known.unwrap().pop().unwrap()
+    }
+
+    /// Checks what the [`Cache`] docs promise. Each call to `open` opens the same storage again,
+    /// reporting updates if asked to.
+    pub(crate) async fn conformance<C, F>(open: impl Fn(bool) -> F)
     where
-        Self: Sized,
+        C: Cache,
+        F: Future<Output = C>,
     {
-        async { Ok(()) }
+        let hunter = spoiler("gingerbreadhunter");
+        let firebolt = spoiler("ragingfirebolt");
+
+        let mut cache = open(false).await;
+        assert!(is_new(&mut cache, &hunter).await);
+        cache
+            .mark_seen(std::slice::from_ref(&hunter))
+            .await
+            .unwrap();
+        assert!(!is_new(&mut cache, &hunter).await);
+        assert!(is_new(&mut open(false).await, &hunter).await);
+        cache.commit().await.unwrap();
+        assert!(!is_new(&mut open(false).await, &hunter).await);
+
+        cache
+            .mark_seen(std::slice::from_ref(&firebolt))
+            .await
+            .unwrap();
+        cache.rollback().await.unwrap();
+        assert!(is_new(&mut cache, &firebolt).await);
+        cache.commit().await.unwrap();
+        assert!(is_new(&mut open(false).await, &firebolt).await);
+
+        // dropped without committing
+        let mut other = open(false).await;
+        other.forget(std::slice::from_ref(&hunter)).await.unwrap();
+        assert!(is_new(&mut other, &hunter).await);
+        drop(other);
+        assert!(!is_new(&mut open(false).await, &hunter).await);
+        cache.forget(std::slice::from_ref(&hunter)).await.unwrap();
+        cache.commit().await.unwrap();
+        assert!(is_new(&mut open(false).await, &hunter).await);
+
+        let known = Known {
+            spoiler: Spoiler {
+                name: Some("Raging Firebolt".into()),
+                ..firebolt.clone()
+            },
+            card: None,
+        };
+        let mut quiet = cache;
+        quiet.remember(std::slice::from_ref(&known)).await.unwrap();
+        assert!(!is_new(&mut quiet, &firebolt).await);
+        assert_eq!(known_of(&mut quiet, &firebolt).await, None);
+        quiet.rollback().await.unwrap();
+
+        let mut cache = open(true).await;
+        cache
+            .mark_seen(std::slice::from_ref(&firebolt))
+            .await
+            .unwrap();
+        assert_eq!(known_of(&mut cache, &firebolt).await, None);
+        cache.remember(std::slice::from_ref(&known)).await.unwrap();
+        assert_eq!(known_of(&mut cache, &firebolt).await, Some(known.clone()));
+        cache.commit().await.unwrap();
+        let mut saved = open(true).await;
+        assert!(!is_new(&mut saved, &firebolt).await);
+        assert_eq!(known_of(&mut saved, &firebolt).await, Some(known));
+    }
+
+    /// Remembers images, which sites don't share. Its clones share what it committed.
+    #[derive(Clone, Default)]
+    pub(crate) struct Images {
+        /// `true` if seen and `false` if forgotten.
+        staged: HashMap<String, bool>,
+        committed: Arc<Mutex<HashSet<String>>>,
+    }
+
+    impl Images {
+        pub(crate) fn new<'a>(seen: impl IntoIterator<Item = &'a str>) -> Self {
+            let committed = seen.into_iter().map(str::to_owned).collect();
+            Self {
+                staged: HashMap::new(),
+                committed: Arc::new(Mutex::new(committed)),
+            }
+        }
+
+        /// Whether `image` was committed as seen.
+        pub(crate) fn contains(&self, image: &str) -> bool {
+            self.committed.lock().unwrap().contains(image)
+        }
+
+        /// How many images were committed as seen.
+        pub(crate) fn committed(&self) -> usize {
+            self.committed.lock().unwrap().len()
+        }
+    }
+
+    impl Cache for Images {
+        async fn filter_new(&mut self, spoilers: &[Spoiler]) -> Result<Vec<Spoiler>, Error> {
+            Ok(spoilers
+                .iter()
+                .filter(|s| {
+                    let image = s.image.as_str();
+                    !self
+                        .staged
+                        .get(image)
+                        .copied()
+                        .unwrap_or_else(|| self.contains(image))
+                })
+                .cloned()
+                .collect())
+        }
+
+        async fn mark_seen(&mut self, spoilers: &[Spoiler]) -> Result<(), Error> {
+            self.staged
+                .extend(spoilers.iter().map(|s| (s.image.to_string(), true)));
+            Ok(())
+        }
+
+        async fn forget(&mut self, spoilers: &[Spoiler]) -> Result<(), Error> {
+            self.staged
+                .extend(spoilers.iter().map(|s| (s.image.to_string(), false)));
+            Ok(())
+        }
+
+        async fn commit(&mut self) -> Result<(), Error> {
+            let mut committed = self.committed.lock().unwrap();
+            for (image, seen) in self.staged.drain() {
+                if seen {
+                    committed.insert(image);
+                } else {
+                    committed.remove(&image);
+                }
+            }
+            Ok(())
+        }
+
+        async fn rollback(&mut self) -> Result<(), Error> {
+            self.staged.clear();
+            Ok(())
+        }
     }
 }
@@ -1,10 +1,19 @@
-use crate::Spoiler;
+use crate::{Error, Spoiler};
 
+/// A cache that never remembers anything, every spoiler is new.
 #[derive(Debug)]
 pub struct Empty;
 
 impl super::Cache for Empty {
-    fn is_new(&mut self, _: &Spoiler) -> bool {
-        true
+    async fn filter_new(&mut self, spoilers: &[Spoiler]) -> Result<Vec<Spoiler>, Error> {
+        Ok(spoilers.to_vec())
+    }
+
+    async fn mark_seen(&mut self, _: &[Spoiler]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn forget(&mut self, _: &[Spoiler]) -> Result<(), Error> {
+        Ok(())
     }
 }
@@ -1,39 +1,185 @@
 use std::{
-    collections::HashSet,
+    collections::{hash_map, HashMap},
     io,
     path::{Path, PathBuf},
-    time::Duration,
+    time::SystemTime,
 };
 
+use serde::{Deserialize, Serialize};
 use tokio::{
     fs,
     io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
 };
 
-use super::Cache;
+use reqwest::Url;
 
+use super::{Cache, Known};
+use crate::{canonical, normalize, slug, Error, Spoiler};
+
+/// Version of the file format, written on its first line.
+const VERSION: u32 = 1;
+
+/// Remembers cards by their [`key`](Spoiler::key), along with when they were first and last
+/// listed, where, and once they've been resolved everything that was [`Known`] of them.
+///
+/// The file is JSON lines, a `{"version":1}` header followed by one object per card. Files
+/// written before it had a version, lists of image urls, are still read and written back in the
+/// current format, their cards seen at the time of the migration.
+///
+/// Only [`commit`](Cache::commit) writes to the file.
 pub struct File {
-    set: HashSet<String>,
+    cards: HashMap<String, Record>,
+    /// Changes since the last commit, `None` for forgotten cards.
+    staged: HashMap<String, Option<Record>>,
+    /// When cards already seen were listed again since the last commit.
+    listed: HashMap<String, u64>,
     path: PathBuf,
+    updates: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    version: u32,
+}
+
+/// A card, as written to the file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Record {
+    key: String,
+    /// Unix time of the first listing the card was in.
+    first_seen: u64,
+    /// Unix time of the last listing the card was in.
+    last_seen: u64,
+    /// Host of the site the card was listed on.
+    site: Option<String>,
+    name: Option<String>,
+    set: Option<String>,
+    image: Url,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    known: Option<Known>,
+}
+
+impl Record {
+    fn listed(key: String, spoiler: &Spoiler, now: u64) -> Self {
+        Self {
+            key,
+            first_seen: now,
+            last_seen: now,
+            site: spoiler.source_site_url.host_str().map(str::to_owned),
+            name: spoiler.name.clone(),
+            set: spoiler.set_code.clone(),
+            image: canonical(&spoiler.image),
+            known: None,
+        }
+    }
+
+    /// A card from a file without a version, which only had its image.
+    fn migrated(image: Url, now: u64) -> Self {
+        let key = key_of_image(&image);
+        Self {
+            set: key.split_once('/').map(|(set, _)| set.to_owned()),
+            site: image.host_str().map(str::to_owned),
+            key,
+            first_seen: now,
+            last_seen: now,
+            name: None,
+            image,
+            known: None,
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
 }
 
 impl File {
-    pub async fn new<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+    /// Loads the cache at `path`, which doesn't have to exist yet.
+    ///
+    /// Fails with [`Error::CacheCorrupted`] if the file isn't a list of cards, or was written
+    /// by a newer version of this crate.
+    pub async fn new<P: Into<PathBuf>>(path: P) -> Result<Self, Error> {
         let path = path.into();
-        let set = Self::load(&path).await?;
-        log::trace!("loaded {} cards", set.len());
-        Ok(Self { set, path })
+        let cards = Self::load(&path).await?;
+        log::trace!("loaded {} cards", cards.len());
+        Ok(Self {
+            cards,
+            staged: HashMap::new(),
+            listed: HashMap::new(),
+            path,
+            updates: false,
+        })
+    }
+
+    /// Reports what changed about the cards it remembers, see [`Seen::Updated`](super::Seen).
+    ///
+    /// Cards only seen in an older cache, or never resolved, aren't reported.
+    pub fn updates(mut self, updates: bool) -> Self {
+        self.updates = updates;
+        self
     }
 
-    async fn load<P: AsRef<Path>>(p: P) -> io::Result<HashSet<String>> {
-        let mut file = match fs::File::open(p).await {
+    /// The card with `key`, staged changes included.
+    fn current(&self, key: &str) -> Option<&Record> {
+        match self.staged.get(key) {
+            Some(staged) => staged.as_ref(),
+            None => self.cards.get(key),
+        }
+    }
+
+    async fn load(path: &Path) -> Result<HashMap<String, Record>, Error> {
+        let mut file = match fs::File::open(path).await {
             Ok(f) => f,
             Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Default::default()),
-            Err(e) => return Err(e),
+            Err(e) => return Err(e.into()),
+        };
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).await?;
+        let corrupted = |line: usize, context: String| Error::CacheCorrupted {
+            path: path.to_owned(),
+            line,
+            context,
         };
-        let mut buf = String::new();
-        file.read_to_string(&mut buf).await?;
-        Ok(buf.split_whitespace().map(ToOwned::to_owned).collect())
+        let buf = String::from_utf8(buf).map_err(|e| {
+            let valid = &e.as_bytes()[..e.utf8_error().valid_up_to()];
+            let line = valid.iter().filter(|&&b| b == b'\n').count() + 1;
+            corrupted(line, e.utf8_error().to_string())
+        })?;
+        let mut lines = buf
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty())
+            .peekable();
+        let Some(&(first, header)) = lines.peek() else {
+            return Ok(Default::default());
+        };
+        if !header.starts_with('{') {
+            log::info!("migrating {} to version {VERSION}", path.display());
+            return migrate(lines, now()).map_err(|(line, context)| corrupted(line, context));
+        }
+
+        let header = serde_json::from_str::<Header>(header)
+            .map_err(|e| corrupted(first, format!("not a header: {e}")))?;
+        if header.version > VERSION {
+            return Err(corrupted(
+                first,
+                format!(
+                    "version {} is newer than this one, {VERSION}",
+                    header.version
+                ),
+            ));
+        }
+        lines
+            .skip(1)
+            .map(|(i, line)| {
+                let record = serde_json::from_str::<Record>(line)
+                    .map_err(|e| corrupted(i, format!("not a card: {e}")))?;
+                Ok((record.key.clone(), record))
+            })
+            .collect()
     }
 
     async fn save<W, I>(mut to: W, set: I) -> io::Result<()>
@@ -51,47 +197,391 @@ impl File {
     }
 }
 
+/// Reads the images of a file without a version, failing with the line that isn't one.
+fn migrate<'a>(
+    lines: impl Iterator<Item = (usize, &'a str)>,
+    now: u64,
+) -> Result<HashMap<String, Record>, (usize, String)> {
+    let mut cards = HashMap::new();
+    for (i, line) in lines {
+        for link in line.split_whitespace() {
+            let image = Url::parse(link).map_err(|e| (i, format!("{link:?} isn't a url: {e}")))?;
+            let record = Record::migrated(canonical(&image), now);
+            cards.insert(record.key.clone(), record);
+        }
+    }
+    Ok(cards)
+}
+
+/// The [`key`](Spoiler::key) of the card `image` is of, assuming sites name images after their
+/// cards and put them in `{set}/cards/`.
+fn key_of_image(image: &Url) -> String {
+    let name = slug(image).map(normalize).unwrap_or_default();
+    let segments = image
+        .path_segments()
+        .map(|s| s.filter(|s| !s.is_empty()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    match segments[..] {
+        [.., set, "cards", _] => format!("{}/{name}", set.to_lowercase()),
+        _ => name,
+    }
+}
+
 impl Cache for File {
-    fn is_new(&mut self, spoiler: &crate::Spoiler) -> bool {
-        self.set.insert(spoiler.image.clone())
+    async fn filter_new(&mut self, spoilers: &[Spoiler]) -> Result<Vec<Spoiler>, Error> {
+        let now = now();
+        let mut new = Vec::new();
+        for spoiler in spoilers {
+            let key = spoiler.key();
+            if self.current(&key).is_none() {
+                new.push(spoiler.clone());
+            } else {
+                self.listed.insert(key, now);
+            }
+        }
+        Ok(new)
+    }
+
+    async fn mark_seen(&mut self, spoilers: &[Spoiler]) -> Result<(), Error> {
+        let now = now();
+        for spoiler in spoilers {
+            let key = spoiler.key();
+            let mut card = match self.current(&key) {
+                Some(card) => card.clone(),
+                None => Record::listed(key.clone(), spoiler, now),
+            };
+            card.last_seen = now;
+            self.staged.insert(key, Some(card));
+        }
+        Ok(())
+    }
+
+    async fn forget(&mut self, spoilers: &[Spoiler]) -> Result<(), Error> {
+        for spoiler in spoilers {
+            self.staged.insert(spoiler.key(), None);
+        }
+        Ok(())
     }
 
-    async fn persist(self) -> io::Result<()> {
-        let base = self.path.parent().unwrap_or_else(|| Path::new("/"));
-        let tmp = match tempfile::NamedTempFile::new_in(base) {
-            Ok(tmp) => tmp,
-            Err(e) => {
-                log::error!("[mtg-spoilers] failed to create temporary file, writing to original file: {e:?}");
-                return fallback(self.set, self.path).await;
+    async fn known(&mut self, spoilers: &[Spoiler]) -> Result<Vec<Option<Known>>, Error> {
+        Ok(spoilers
+            .iter()
+            .map(|s| {
+                let known = self.current(&s.key())?.known.as_ref();
+                known.filter(|_| self.updates).cloned()
+            })
+            .collect())
+    }
+
+    async fn remember(&mut self, known: &[Known]) -> Result<(), Error> {
+        let now = now();
+        for known in known {
+            let key = known.spoiler.key();
+            let mut card = match self.current(&key) {
+                Some(card) => card.clone(),
+                None => Record::listed(key.clone(), &known.spoiler, now),
+            };
+            card.last_seen = now;
+            card.name = known.spoiler.name.clone().or(card.name);
+            card.set = known.spoiler.set_code.clone().or(card.set);
+            card.image = canonical(&known.spoiler.image);
+            card.known = Some(known.clone());
+            self.staged.insert(key, Some(card));
+        }
+        Ok(())
+    }
+
+    /// Writes the cards next to the file and renames that over it, syncing both to disk, so
+    /// that the file is whole even if the machine goes down halfway.
+    ///
+    /// Committing waits for any other process committing to the same file, and only writes this
+    /// one's changes over the file as it is then, so what others saved or forgot since this one
+    /// was loaded stays that way.
+    async fn commit(&mut self) -> Result<(), Error> {
+        let _lock = lock(&self.path).await?;
+        let mut saved = Self::load(&self.path).await?;
+        let mut cards = HashMap::new();
+        for (key, staged) in &self.staged {
+            match staged {
+                Some(card) => cards.insert(key.clone(), card.clone()),
+                None => saved.remove(key),
+            };
+        }
+        merge(&mut cards, saved);
+        for (key, &listed) in &self.listed {
+            if let Some(card) = cards.get_mut(key) {
+                card.last_seen = card.last_seen.max(listed);
             }
+        }
+
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
         };
-        let (tmp_file, tmp_path) = tmp.into_parts();
-        let writer = BufWriter::new(fs::File::from_std(tmp_file));
-        if let Err(e) = Self::save(writer, self.set.iter()).await {
-            log::error!("[mtg-spoilers] couldn't save to tmp file: {e:?}");
-            return fallback(self.set, self.path).await;
-        }
-        tokio::time::sleep(Duration::from_secs(60)).await;
-        if let Err(e) = tokio::fs::rename(tmp_path, &self.path).await {
-            log::error!("[mtg-spoilers] overwrite original file: {e:?}");
-            return fallback(self.set, self.path).await;
-        }
-        return Ok(());
-
-        async fn fallback(set: HashSet<String>, path: PathBuf) -> io::Result<()> {
-            let file = match fs::File::create(&path).await {
-                Ok(file) => file,
-                Err(e) => {
-                    log::error!("[mtg-spoilers] can't open original file: {e:?}");
-                    return Err(e);
-                }
-            };
-            let writer = BufWriter::new(file);
-            if let Err(e) = File::save(writer, set).await {
-                log::error!("[mtg-spoilers] can't write to original file: {e:?}");
-                return Err(e);
+        let (tmp_file, tmp_path) = tempfile::NamedTempFile::new_in(dir)?.into_parts();
+        let mut tmp_file = fs::File::from_std(tmp_file);
+        Self::save(BufWriter::new(&mut tmp_file), lines(&cards)).await?;
+        tmp_file.sync_all().await?;
+        tmp_path.persist(&self.path).map_err(io::Error::from)?;
+        sync_dir(dir).await?;
+        log::trace!("saved {} cards", cards.len());
+        self.cards = cards;
+        self.staged.clear();
+        self.listed.clear();
+        Ok(())
+    }
+
+    async fn rollback(&mut self) -> Result<(), Error> {
+        self.staged.clear();
+        self.listed.clear();
+        Ok(())
+    }
+}
+
+/// Locks `{path}.lock` until the returned file is dropped, waiting for whoever has it.
+///
+/// The cache itself is replaced on every persist, so a lock on it wouldn't last.
+async fn lock(path: &Path) -> io::Result<std::fs::File> {
+    let mut lock = path.as_os_str().to_owned();
+    lock.push(".lock");
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(lock)?;
+        file.lock()?;
+        Ok(file)
+    })
+    .await?
+}
+
+/// Makes a rename in `dir` durable, which takes syncing the directory itself on unix.
+async fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    fs::File::open(dir).await?.sync_all().await?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Adds the cards only `saved` has to `cards`, and what only `saved` knows of the others.
+fn merge(cards: &mut HashMap<String, Record>, saved: HashMap<String, Record>) {
+    for (key, saved) in saved {
+        match cards.entry(key) {
+            hash_map::Entry::Vacant(card) => {
+                card.insert(saved);
+            }
+            hash_map::Entry::Occupied(mut card) => {
+                let card = card.get_mut();
+                card.first_seen = card.first_seen.min(saved.first_seen);
+                card.last_seen = card.last_seen.max(saved.last_seen);
+                card.name = card.name.take().or(saved.name);
+                card.set = card.set.take().or(saved.set);
+                card.known = card.known.take().or(saved.known);
+            }
+        }
+    }
+}
+
+/// The file's lines, its header then its cards sorted by key.
+fn lines(cards: &HashMap<String, Record>) -> Vec<String> {
+    let mut records = cards.values().collect::<Vec<_>>();
+    records.sort_by(|a, b| a.key.cmp(&b.key));
+    let header = Header { version: VERSION };
+    std::iter::once(serde_json::to_string(&header))
+        .chain(records.into_iter().map(serde_json::to_string))
+        .collect::<Result<_, _>>()
+        .expect("cards serialize to json")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::{
+        self,
+        test::{is_new, spoiler},
+    };
+
+    #[tokio::test]
+    async fn conformance() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache");
+        cache::test::conformance(|updates| {
+            let path = path.clone();
+            async move { File::new(path).await.unwrap().updates(updates) }
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn unversioned_files_are_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache");
+        fs::write(
+            &path,
+            "https://mythicspoiler.com/woe/cards/gingerbreadhunter.jpg\n\
+             http://mythicspoiler.com//woe/cards/ragingfirebolt.jpg\n",
+        )
+        .await
+        .unwrap();
+        File::new(&path).await.unwrap().commit().await.unwrap();
+
+        let saved = fs::read_to_string(&path).await.unwrap();
+        let mut lines = saved.lines();
+        assert_eq!(lines.next(), Some(r#"{"version":1}"#));
+        let records = lines
+            .map(|l| serde_json::from_str::<Record>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            records.iter().map(|r| &r.key[..]).collect::<Vec<_>>(),
+            ["woe/gingerbreadhunter", "woe/ragingfirebolt"]
+        );
+        for record in &records {
+            assert_eq!(record.set.as_deref(), Some("woe"));
+            assert_eq!(record.site.as_deref(), Some("mythicspoiler.com"));
+            assert!(record.first_seen > 0);
+        }
+
+        let mut cache = File::new(&path).await.unwrap();
+        assert_eq!(cache.cards.len(), 2);
+        cache.cards.values_mut().for_each(|r| r.last_seen = 0);
+        assert!(!is_new(&mut cache, &spoiler("gingerbreadhunter")).await);
+        cache.commit().await.unwrap();
+        let hunter = &cache.cards["woe/gingerbreadhunter"];
+        assert_eq!(hunter.first_seen, records[0].first_seen);
+        assert!(hunter.last_seen > 0);
+    }
+
+    #[tokio::test]
+    async fn listing_is_staged_until_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache");
+        let hunter = spoiler("gingerbreadhunter");
+        let cards = HashMap::from([(hunter.key(), Record::listed(hunter.key(), &hunter, 0))]);
+        fs::write(&path, lines(&cards).join("\n")).await.unwrap();
+        let last_seen = || async { File::new(&path).await.unwrap().cards[&hunter.key()].last_seen };
+
+        let mut cache = File::new(&path).await.unwrap();
+        assert!(!is_new(&mut cache, &hunter).await);
+        cache.rollback().await.unwrap();
+        cache.commit().await.unwrap();
+        assert_eq!(cache.cards[&hunter.key()].last_seen, 0);
+        assert_eq!(last_seen().await, 0);
+
+        assert!(!is_new(&mut cache, &hunter).await);
+        cache.commit().await.unwrap();
+        assert!(last_seen().await > 0);
+    }
+
+    #[tokio::test]
+    async fn concurrent_commits_keep_every_card() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache");
+        let mut one = File::new(&path).await.unwrap();
+        let mut other = File::new(&path).await.unwrap();
+        one.mark_seen(&[spoiler("gingerbreadhunter")])
+            .await
+            .unwrap();
+        other.mark_seen(&[spoiler("ragingfirebolt")]).await.unwrap();
+        let (one, other) = tokio::join!(one.commit(), other.commit());
+        one.unwrap();
+        other.unwrap();
+
+        let saved = File::new(&path).await.unwrap();
+        let mut keys = saved.cards.keys().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, ["woe/gingerbreadhunter", "woe/ragingfirebolt"]);
+
+        let mut files = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|f| f.unwrap().file_name())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, ["cache", "cache.lock"]);
+    }
+
+    #[tokio::test]
+    async fn cards_forgotten_elsewhere_stay_forgotten() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache");
+        let mut cache = File::new(&path).await.unwrap();
+        cache
+            .mark_seen(&[spoiler("gingerbreadhunter")])
+            .await
+            .unwrap();
+        cache.commit().await.unwrap();
+
+        let mut one = File::new(&path).await.unwrap();
+        let mut other = File::new(&path).await.unwrap();
+        other.forget(&[spoiler("gingerbreadhunter")]).await.unwrap();
+        other.commit().await.unwrap();
+        one.mark_seen(&[spoiler("ragingfirebolt")]).await.unwrap();
+        one.commit().await.unwrap();
+
+        let mut saved = File::new(&path).await.unwrap();
+        assert!(is_new(&mut saved, &spoiler("gingerbreadhunter")).await);
+        assert!(!is_new(&mut saved, &spoiler("ragingfirebolt")).await);
+    }
+
+    #[tokio::test]
+    async fn urls_from_older_caches_still_match() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache");
+        fs::write(&path, "http://mythicspoiler.com//woe/cards/a.jpg\n")
+            .await
+            .unwrap();
+        let mut cache = File::new(&path).await.unwrap();
+        assert!(!is_new(&mut cache, &spoiler("a")).await);
+    }
+
+    #[tokio::test]
+    async fn corrupted_files_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache");
+
+        assert!(File::new(&path).await.unwrap().cards.is_empty());
+
+        fs::write(&path, "https://mythicspoiler.com/woe/cards/a.jpg\n")
+            .await
+            .unwrap();
+        assert_eq!(File::new(&path).await.unwrap().cards.len(), 1);
+
+        fs::write(
+            &path,
+            "https://mythicspoiler.com/woe/cards/a.jpg\n\0\0garbage\n",
+        )
+        .await
+        .unwrap();
+        match File::new(&path).await.err() {
+            Some(Error::CacheCorrupted { line: 2, .. }) => {}
+            other => panic!("expected a corrupted cache, got {other:?}"),
+        }
+
+        fs::write(&path, b"https://mythicspoiler.com/a.jpg\n\xff\xfe")
+            .await
+            .unwrap();
+        match File::new(&path).await.err() {
+            Some(Error::CacheCorrupted { line: 2, .. }) => {}
+            other => panic!("expected a corrupted cache, got {other:?}"),
+        }
+
+        fs::write(&path, "{\"version\":1}\n\n{\"key\":\"woe/a\"}\n")
+            .await
+            .unwrap();
+        match File::new(&path).await.err() {
+            Some(Error::CacheCorrupted { line: 3, .. }) => {}
+            other => panic!("expected a corrupted cache, got {other:?}"),
+        }
+
+        fs::write(&path, "{\"version\":2}\n").await.unwrap();
+        match File::new(&path).await.err() {
+            Some(Error::CacheCorrupted {
+                line: 1, context, ..
+            }) => {
+                assert!(context.contains("newer"), "{context}")
             }
-            Ok(())
+            other => panic!("expected a corrupted cache, got {other:?}"),
         }
     }
 }
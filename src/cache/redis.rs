@@ -0,0 +1,561 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, SystemTime},
+};
+
+use ::redis::{
+    aio::ConnectionLike, aio::MultiplexedConnection, Client, ErrorKind, Pipeline, RedisError,
+};
+
+use super::{Cache, Known};
+use crate::{Error, Spoiler};
+
+/// Remembers cards in Redis, or anything that speaks its protocol, so that processes on several
+/// hosts share what they've seen.
+///
+/// Each site gets a sorted set, `{prefix}:{host}`, of the [keys](Spoiler::key) of its cards
+/// scored by when they were last listed. With a [`ttl`](Self::ttl), cards that weren't listed
+/// for that long are forgotten. What's [`Known`] of them is in a hash next to it,
+/// `{prefix}:{host}:known`, as JSON. Changes are staged in memory and written in a single
+/// `MULTI` on [`commit`](Cache::commit).
+pub struct Redis<C = MultiplexedConnection> {
+    connection: C,
+    prefix: String,
+    ttl: Option<Duration>,
+    updates: bool,
+    /// Changes by set and card key.
+    staged: HashMap<(String, String), Staged>,
+    /// Cards that were listed again, to push their expiry back.
+    touched: HashSet<(String, String)>,
+}
+
+enum Staged {
+    Seen,
+    Known(Box<Known>),
+    Forgotten,
+}
+
+impl Redis {
+    /// Connects to the server at `url`, e.g. `redis://127.0.0.1/`.
+    pub async fn open(url: &str) -> Result<Self, Error> {
+        let connection = Client::open(url)?
+            .get_multiplexed_tokio_connection()
+            .await?;
+        Ok(Self::new(connection))
+    }
+}
+
+impl<C: ConnectionLike + Send> Redis<C> {
+    pub fn new(connection: C) -> Self {
+        Self {
+            connection,
+            prefix: "mtg-spoilers".into(),
+            ttl: None,
+            updates: false,
+            staged: HashMap::new(),
+            touched: HashSet::new(),
+        }
+    }
+
+    /// What the sets' names start with, to share a server with other caches. Defaults to
+    /// `mtg-spoilers`.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Forget cards that weren't listed for `ttl`, so that the sets don't grow forever.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Reports what changed about the cards it remembers, see [`Seen::Updated`](super::Seen).
+    pub fn updates(mut self, updates: bool) -> Self {
+        self.updates = updates;
+        self
+    }
+
+    /// The name of the set the card of `spoiler` is in, and its key in there.
+    fn member(&self, spoiler: &Spoiler) -> (String, String) {
+        let host = spoiler.source_site_url.host_str().unwrap_or_default();
+        (format!("{}:{host}", self.prefix), spoiler.key())
+    }
+
+    /// When cards last listed before are expired, if they are.
+    fn expired_before(&self, now: i64) -> Option<i64> {
+        self.ttl.map(|ttl| now - ttl.as_secs() as i64)
+    }
+}
+
+/// The hash what's known of the cards of `set` is in.
+fn known_of(set: &str) -> String {
+    format!("{set}:known")
+}
+
+fn unix(time: SystemTime) -> i64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+impl<C: ConnectionLike + Send> Cache for Redis<C> {
+    async fn filter_new(&mut self, spoilers: &[Spoiler]) -> Result<Vec<Spoiler>, Error> {
+        let members = spoilers.iter().map(|s| self.member(s)).collect::<Vec<_>>();
+        let unstaged = members
+            .iter()
+            .filter(|m| !self.staged.contains_key(*m))
+            .collect::<Vec<_>>();
+        let mut pipeline = Pipeline::new();
+        for (set, key) in &unstaged {
+            pipeline.cmd("ZSCORE").arg(set).arg(key);
+        }
+        let scores: Vec<Option<f64>> = pipeline.query_async(&mut self.connection).await?;
+        let expired_before = self.expired_before(unix(SystemTime::now()));
+        let mut scores = scores.into_iter();
+
+        let mut new = Vec::new();
+        for (spoiler, member) in spoilers.iter().zip(members) {
+            let seen = match self.staged.get(&member) {
+                Some(staged) => !matches!(staged, Staged::Forgotten),
+                None => scores
+                    .next()
+                    .flatten()
+                    .is_some_and(|s| expired_before.is_none_or(|e| s as i64 >= e)),
+            };
+            if seen {
+                self.touched.insert(member);
+            } else {
+                new.push(spoiler.clone());
+            }
+        }
+        Ok(new)
+    }
+
+    async fn mark_seen(&mut self, spoilers: &[Spoiler]) -> Result<(), Error> {
+        for spoiler in spoilers {
+            let member = self.member(spoiler);
+            // what's known of it says it's been seen already
+            if !matches!(self.staged.get(&member), Some(Staged::Known(_))) {
+                self.staged.insert(member, Staged::Seen);
+            }
+        }
+        Ok(())
+    }
+
+    async fn forget(&mut self, spoilers: &[Spoiler]) -> Result<(), Error> {
+        for spoiler in spoilers {
+            self.staged.insert(self.member(spoiler), Staged::Forgotten);
+        }
+        Ok(())
+    }
+
+    async fn known(&mut self, spoilers: &[Spoiler]) -> Result<Vec<Option<Known>>, Error> {
+        if !self.updates {
+            return Ok(vec![None; spoilers.len()]);
+        }
+        let members = spoilers.iter().map(|s| self.member(s)).collect::<Vec<_>>();
+        let mut pipeline = Pipeline::new();
+        for (set, key) in members
+            .iter()
+            .filter(|m| matches!(self.staged.get(*m), Some(Staged::Seen) | None))
+        {
+            pipeline.hget(known_of(set), key);
+        }
+        let saved: Vec<Option<String>> = pipeline.query_async(&mut self.connection).await?;
+        let mut saved = saved.into_iter();
+
+        members
+            .iter()
+            .map(|member| match self.staged.get(member) {
+                Some(Staged::Known(known)) => Ok(Some(Known::clone(known))),
+                Some(Staged::Forgotten) => Ok(None),
+                Some(Staged::Seen) | None => {
+                    let known = saved.next().flatten();
+                    let known = known
+                        .map(|known| serde_json::from_str(&known))
+                        .transpose()
+                        .map_err(|e| {
+                            let context = format!("{}: {e}", member.1);
+                            RedisError::from((ErrorKind::TypeError, "not a known card", context))
+                        })?;
+                    Ok(known)
+                }
+            })
+            .collect()
+    }
+
+    async fn remember(&mut self, known: &[Known]) -> Result<(), Error> {
+        for known in known {
+            let member = self.member(&known.spoiler);
+            self.staged
+                .insert(member, Staged::Known(Box::new(known.clone())));
+        }
+        Ok(())
+    }
+
+    /// Writes every staged change in a single transaction, and drops the expired cards of the
+    /// sets it touched.
+    async fn commit(&mut self) -> Result<(), Error> {
+        let now = unix(SystemTime::now());
+        let mut pipeline = Pipeline::new();
+        pipeline.atomic();
+        let mut sets = HashSet::new();
+        for ((set, key), staged) in &self.staged {
+            match staged {
+                Staged::Seen => {
+                    pipeline.zadd(set, key, now).ignore();
+                }
+                Staged::Known(known) => {
+                    let known = serde_json::to_string(known).expect("cards serialize to json");
+                    pipeline.zadd(set, key, now).ignore();
+                    pipeline.hset(known_of(set), key, known).ignore();
+                }
+                Staged::Forgotten => {
+                    pipeline.zrem(set, key).ignore();
+                    pipeline.hdel(known_of(set), key).ignore();
+                }
+            }
+            sets.insert(set);
+        }
+        // only those still there, another process may have forgotten them
+        for (set, key) in self
+            .touched
+            .iter()
+            .filter(|m| !self.staged.contains_key(*m))
+        {
+            pipeline
+                .cmd("ZADD")
+                .arg(set)
+                .arg("XX")
+                .arg(now)
+                .arg(key)
+                .ignore();
+            sets.insert(set);
+        }
+        if let (Some(ttl), Some(expired_before)) = (self.ttl, self.expired_before(now)) {
+            let sets = sets.into_iter().collect::<Vec<_>>();
+            // sets have no way to drop the hash fields of their members, so those are looked
+            // up first
+            let mut expired = Pipeline::new();
+            for set in &sets {
+                expired.zrangebyscore(*set, "-inf", format!("({expired_before}"));
+            }
+            let expired: Vec<Vec<String>> = expired.query_async(&mut self.connection).await?;
+            for (set, mut expired) in sets.into_iter().zip(expired) {
+                // those listed again since are written back above
+                expired.retain(|key| !self.staged.contains_key(&(set.clone(), key.clone())));
+                if !expired.is_empty() {
+                    pipeline.hdel(known_of(set), expired).ignore();
+                }
+                pipeline
+                    .zrembyscore(set, "-inf", format!("({expired_before}"))
+                    .ignore();
+                // a site nothing was listed of for that long is gone altogether
+                pipeline.expire(set, ttl.as_secs() as i64).ignore();
+                pipeline
+                    .expire(known_of(set), ttl.as_secs() as i64)
+                    .ignore();
+            }
+        }
+        if pipeline.cmd_iter().next().is_some() {
+            tracing::trace!(count = self.staged.len(), "committing cache");
+            pipeline.query_async::<()>(&mut self.connection).await?;
+        }
+        self.staged.clear();
+        self.touched.clear();
+        Ok(())
+    }
+
+    async fn rollback(&mut self) -> Result<(), Error> {
+        self.staged.clear();
+        self.touched.clear();
+        Ok(())
+    }
+}
+
+#[allow(dead_code)]
+fn _assert(connection: MultiplexedConnection) {
+    fn is_send<T: Send>(_: T) {}
+    is_send(Redis::open("redis://127.0.0.1/"));
+    is_send(crate::site::new_cards(
+        &crate::mythic::Mythic::default(),
+        Redis::new(connection),
+    ));
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use ::redis::{Cmd, RedisFuture, RedisResult, Value};
+    use futures::FutureExt;
+    use reqwest::Url;
+
+    use super::*;
+    use crate::cache::{
+        self,
+        test::{is_new, spoiler},
+    };
+
+    /// Just enough of Redis for the cache, shared by its clones.
+    #[derive(Clone, Default)]
+    struct Mock {
+        sets: Arc<Mutex<HashMap<String, HashMap<String, f64>>>>,
+        hashes: Arc<Mutex<HashMap<String, HashMap<String, String>>>>,
+        expiries: Arc<Mutex<HashMap<String, i64>>>,
+    }
+
+    impl Mock {
+        fn run(&self, cmd: &Cmd) -> RedisResult<Value> {
+            let args = cmd
+                .args_iter()
+                .map(|a| match a {
+                    ::redis::Arg::Simple(a) => String::from_utf8_lossy(a).into_owned(),
+                    ::redis::Arg::Cursor => "<cursor>".into(),
+                })
+                .collect::<Vec<_>>();
+            let mut sets = self.sets.lock().unwrap();
+            let mut hashes = self.hashes.lock().unwrap();
+            let value = match &args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+                ["ZSCORE", set, key] => match sets.get(*set).and_then(|s| s.get(*key)) {
+                    Some(score) => Value::BulkString(score.to_string().into_bytes()),
+                    None => Value::Nil,
+                },
+                ["ZADD", set, score, key] => {
+                    let set = sets.entry(set.to_string()).or_default();
+                    let added = set.insert(key.to_string(), score.parse().unwrap());
+                    Value::Int(added.is_none().into())
+                }
+                ["ZADD", set, "XX", score, key] => {
+                    if let Some(old) = sets.get_mut(*set).and_then(|s| s.get_mut(*key)) {
+                        *old = score.parse().unwrap();
+                    }
+                    Value::Int(0)
+                }
+                ["ZREM", set, key] => {
+                    let removed = sets.get_mut(*set).and_then(|s| s.remove(*key));
+                    Value::Int(removed.is_some().into())
+                }
+                ["ZRANGEBYSCORE", set, "-inf", max] => {
+                    let max: f64 = max.trim_start_matches('(').parse().unwrap();
+                    let set = sets.get(*set).into_iter().flatten();
+                    let keys = set
+                        .filter(|(_, s)| **s < max)
+                        .map(|(key, _)| Value::BulkString(key.clone().into_bytes()));
+                    Value::Array(keys.collect())
+                }
+                ["ZREMRANGEBYSCORE", set, "-inf", max] => {
+                    let max: f64 = max.trim_start_matches('(').parse().unwrap();
+                    let set = sets.entry(set.to_string()).or_default();
+                    let before = set.len();
+                    set.retain(|_, s| *s >= max);
+                    Value::Int((before - set.len()) as i64)
+                }
+                ["HGET", hash, key] => match hashes.get(*hash).and_then(|h| h.get(*key)) {
+                    Some(value) => Value::BulkString(value.clone().into_bytes()),
+                    None => Value::Nil,
+                },
+                ["HSET", hash, key, value] => {
+                    let hash = hashes.entry(hash.to_string()).or_default();
+                    let added = hash.insert(key.to_string(), value.to_string());
+                    Value::Int(added.is_none().into())
+                }
+                ["HDEL", hash, keys @ ..] => {
+                    let hash = hashes.entry(hash.to_string()).or_default();
+                    let removed = keys.iter().filter(|k| hash.remove(**k).is_some());
+                    Value::Int(removed.count() as i64)
+                }
+                ["EXPIRE", key, ttl] => {
+                    let mut expiries = self.expiries.lock().unwrap();
+                    expiries.insert(key.to_string(), ttl.parse().unwrap());
+                    Value::Int(1)
+                }
+                args => {
+                    let args = format!("{args:?}");
+                    return Err((ErrorKind::ResponseError, "not mocked", args).into());
+                }
+            };
+            Ok(value)
+        }
+    }
+
+    impl ConnectionLike for Mock {
+        fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+            futures::future::ready(self.run(cmd)).boxed()
+        }
+
+        fn req_packed_commands<'a>(
+            &'a mut self,
+            pipeline: &'a Pipeline,
+            offset: usize,
+            _: usize,
+        ) -> RedisFuture<'a, Vec<Value>> {
+            let values: RedisResult<Vec<_>> = pipeline.cmd_iter().map(|c| self.run(c)).collect();
+            // a transaction's replies come all at once, after its EXEC
+            let values = values.map(|values| {
+                if offset > 0 {
+                    vec![Value::Array(values)]
+                } else {
+                    values
+                }
+            });
+            futures::future::ready(values).boxed()
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+    }
+
+    #[tokio::test]
+    async fn conformance() {
+        let mock = Mock::default();
+        cache::test::conformance(|updates| {
+            let cache = Redis::new(mock.clone()).updates(updates);
+            async { cache }
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn forgetting_drops_what_was_known() {
+        let mock = Mock::default();
+        let mut cache = Redis::new(mock.clone()).updates(true);
+        let hunter = spoiler("gingerbreadhunter");
+        let known = Known {
+            spoiler: hunter.clone(),
+            card: None,
+        };
+        cache.remember(&[known]).await.unwrap();
+        cache.commit().await.unwrap();
+        cache.forget(std::slice::from_ref(&hunter)).await.unwrap();
+        cache.commit().await.unwrap();
+        assert!(mock.hashes.lock().unwrap()["mtg-spoilers:mythicspoiler.com:known"].is_empty());
+    }
+
+    #[tokio::test]
+    async fn unexpected_commands_fail() {
+        let mut cache = Redis::new(Mock::default());
+        let e = ::redis::cmd("FLUSHALL")
+            .query_async::<()>(&mut cache.connection)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("FLUSHALL"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn sites_are_namespaced() {
+        let mock = Mock::default();
+        let mut cache = Redis::new(mock.clone()).prefix("bot");
+        let mythic = spoiler("gingerbreadhunter");
+        let magic_spoiler = Spoiler {
+            source_site_url: Url::parse("https://www.magicspoiler.com/woe/gingerbreadhunter")
+                .unwrap(),
+            ..mythic.clone()
+        };
+        assert_eq!(mythic.key(), magic_spoiler.key());
+
+        cache
+            .mark_seen(std::slice::from_ref(&mythic))
+            .await
+            .unwrap();
+        cache.commit().await.unwrap();
+        assert!(is_new(&mut cache, &magic_spoiler).await);
+        let mut sets = mock
+            .sets
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        sets.sort();
+        assert_eq!(sets, ["bot:mythicspoiler.com"]);
+    }
+
+    #[tokio::test]
+    async fn cards_not_listed_for_the_ttl_expire() {
+        let mock = Mock::default();
+        let ttl = Duration::from_secs(60 * 60);
+        let mut cache = Redis::new(mock.clone()).ttl(ttl);
+        let hunter = spoiler("gingerbreadhunter");
+        let firebolt = spoiler("ragingfirebolt");
+        let known = Known {
+            spoiler: firebolt.clone(),
+            card: None,
+        };
+        cache
+            .mark_seen(std::slice::from_ref(&hunter))
+            .await
+            .unwrap();
+        cache.remember(&[known]).await.unwrap();
+        cache.commit().await.unwrap();
+        let expiries = mock.expiries.lock().unwrap().clone();
+        assert_eq!(expiries["mtg-spoilers:mythicspoiler.com"], 60 * 60);
+        assert_eq!(expiries["mtg-spoilers:mythicspoiler.com:known"], 60 * 60);
+
+        let two_hours_ago = unix(SystemTime::now()) as f64 - 2.0 * 60.0 * 60.0;
+        mock.sets
+            .lock()
+            .unwrap()
+            .values_mut()
+            .for_each(|s| s.values_mut().for_each(|score| *score = two_hours_ago));
+        assert!(is_new(&mut cache, &hunter).await);
+
+        // listing it again pushes its expiry back, the firebolt's gone once committed
+        let known = Known {
+            spoiler: hunter.clone(),
+            card: None,
+        };
+        cache.remember(&[known]).await.unwrap();
+        cache.commit().await.unwrap();
+        let set = &mock.sets.lock().unwrap()["mtg-spoilers:mythicspoiler.com"];
+        assert_eq!(set.keys().collect::<Vec<_>>(), ["woe/gingerbreadhunter"]);
+        let known = &mock.hashes.lock().unwrap()["mtg-spoilers:mythicspoiler.com:known"];
+        assert_eq!(known.keys().collect::<Vec<_>>(), ["woe/gingerbreadhunter"]);
+    }
+
+    #[tokio::test]
+    async fn listing_cards_again_keeps_them() {
+        let mock = Mock::default();
+        let mut cache = Redis::new(mock.clone()).ttl(Duration::from_secs(60 * 60));
+        let hunter = spoiler("gingerbreadhunter");
+        cache
+            .mark_seen(std::slice::from_ref(&hunter))
+            .await
+            .unwrap();
+        cache.commit().await.unwrap();
+
+        let half_an_hour_ago = unix(SystemTime::now()) as f64 - 30.0 * 60.0;
+        let score =
+            || mock.sets.lock().unwrap()["mtg-spoilers:mythicspoiler.com"]["woe/gingerbreadhunter"];
+        mock.sets
+            .lock()
+            .unwrap()
+            .values_mut()
+            .for_each(|s| s.values_mut().for_each(|score| *score = half_an_hour_ago));
+        assert!(!is_new(&mut cache, &hunter).await);
+        cache.commit().await.unwrap();
+        assert!(score() > half_an_hour_ago);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a redis-server on localhost"]
+    async fn against_a_server() {
+        let mut cache = Redis::open("redis://127.0.0.1/")
+            .await
+            .unwrap()
+            .prefix(format!("mtg-spoilers-test-{}", unix(SystemTime::now())))
+            .ttl(Duration::from_secs(60));
+        let hunter = spoiler("gingerbreadhunter");
+        assert!(is_new(&mut cache, &hunter).await);
+        cache
+            .mark_seen(std::slice::from_ref(&hunter))
+            .await
+            .unwrap();
+        cache.commit().await.unwrap();
+        assert!(!is_new(&mut cache, &hunter).await);
+        cache.forget(std::slice::from_ref(&hunter)).await.unwrap();
+        cache.commit().await.unwrap();
+        assert!(is_new(&mut cache, &hunter).await);
+    }
+}
@@ -0,0 +1,461 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use reqwest::Url;
+use rusqlite::{params, types::Type, Connection, OptionalExtension, Row};
+
+use super::{Cache, Known};
+use crate::{canonical, Error, Spoiler, SpoilerSource};
+
+/// Version of the schema, kept in the database's `user_version`.
+const VERSION: u32 = 1;
+
+/// Remembers cards in a SQLite database, which several processes can share, and which can tell
+/// which cards were seen when, see [`seen_since`](Self::seen_since).
+///
+/// The database is in WAL mode, reading it doesn't wait for whoever is writing to it. Changes
+/// are staged in memory and written in a single transaction on [`commit`](Cache::commit).
+///
+/// Queries run on tokio's blocking threads, waiting for another process's write doesn't hold up
+/// the runtime.
+pub struct Sqlite {
+    connection: Arc<Mutex<Connection>>,
+    staged: HashMap<String, Staged>,
+    updates: bool,
+}
+
+enum Staged {
+    /// A card already in the database listed again, at this unix time.
+    Listed(i64),
+    Seen(Box<Spoiler>),
+    Known(Box<Known>),
+    Forgotten,
+}
+
+/// A card, as stored in the database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// The card's [`key`](Spoiler::key).
+    pub key: String,
+    /// When the card was first listed.
+    pub first_seen: SystemTime,
+    /// When the card was last listed.
+    pub last_seen: SystemTime,
+    /// Host of the site that listed the card.
+    pub site: Option<String>,
+    /// The card's page on the site.
+    pub page: Url,
+    pub image: Url,
+    pub name: Option<String>,
+    pub set_code: Option<String>,
+    pub source: Option<SpoilerSource>,
+}
+
+impl Sqlite {
+    /// Opens the database at `path`, creating it if it doesn't exist yet.
+    ///
+    /// `":memory:"` opens a database that's gone once this is dropped.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let connection = Connection::open(path)?;
+        connection.busy_timeout(Duration::from_secs(5))?;
+        connection.pragma_update(None, "journal_mode", "wal")?;
+        let version: u32 = connection.pragma_query_value(None, "user_version", |r| r.get(0))?;
+        match version {
+            0 => {
+                connection.execute_batch(
+                    "BEGIN;
+                    CREATE TABLE IF NOT EXISTS cards (
+                        key TEXT PRIMARY KEY,
+                        first_seen INTEGER NOT NULL,
+                        last_seen INTEGER NOT NULL,
+                        site TEXT,
+                        page TEXT NOT NULL,
+                        image TEXT NOT NULL,
+                        name TEXT,
+                        set_code TEXT,
+                        source TEXT,
+                        source_url TEXT,
+                        known TEXT
+                    );
+                    CREATE INDEX IF NOT EXISTS cards_first_seen ON cards (first_seen);
+                    COMMIT;",
+                )?;
+                connection.pragma_update(None, "user_version", VERSION)?;
+            }
+            VERSION => {}
+            newer => {
+                return Err(Error::Config(format!(
+                    "the database's schema is version {newer}, newer than this one, {VERSION}"
+                )))
+            }
+        }
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            staged: HashMap::new(),
+            updates: false,
+        })
+    }
+
+    /// Reports what changed about the cards it remembers, see [`Seen::Updated`](super::Seen).
+    pub fn updates(mut self, updates: bool) -> Self {
+        self.updates = updates;
+        self
+    }
+
+    /// The cards first listed at or after `since`, oldest first, e.g. those of the last 24h
+    /// with `SystemTime::now() - Duration::from_secs(24 * 60 * 60)`.
+    pub fn seen_since(&self, since: SystemTime) -> Result<Vec<Record>, Error> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT key, first_seen, last_seen, site, page, image, name, set_code, source,
+                source_url
+            FROM cards WHERE first_seen >= ?1 ORDER BY first_seen, key",
+        )?;
+        let records = statement
+            .query_map([unix(since)], record)?
+            .collect::<Result<_, _>>()?;
+        Ok(records)
+    }
+
+    /// Runs `query` on the connection in tokio's blocking pool.
+    async fn blocking<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&mut Connection) -> T + Send + 'static,
+    ) -> T {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || query(&mut connection.lock().unwrap()))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+    }
+}
+
+/// Whether the card with `key` is in the database.
+fn contains(connection: &Connection, key: &str) -> Result<bool, Error> {
+    let found = connection
+        .query_row("SELECT 1 FROM cards WHERE key = ?1", [key], |_| Ok(()))
+        .optional()?;
+    Ok(found.is_some())
+}
+
+fn load_known(connection: &Connection, key: &str) -> Result<Option<Known>, Error> {
+    let known = connection
+        .query_row("SELECT known FROM cards WHERE key = ?1", [key], |r| {
+            r.get::<_, Option<String>>(0)
+        })
+        .optional()?
+        .flatten();
+    let known = known
+        .map(|known| serde_json::from_str(&known))
+        .transpose()
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(e)))?;
+    Ok(known)
+}
+
+/// Saves a card as seen, keeping what was known of it unless `known` says more.
+fn save(
+    connection: &Connection,
+    spoiler: &Spoiler,
+    known: Option<&Known>,
+    now: i64,
+) -> Result<(), Error> {
+    let known = known.map(|k| serde_json::to_string(k).expect("spoilers serialize to json"));
+    connection.execute(
+        "INSERT INTO cards (key, first_seen, last_seen, site, page, image, name, set_code,
+            source, source_url, known)
+        VALUES (?1, ?2, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+        ON CONFLICT (key) DO UPDATE SET
+            last_seen = excluded.last_seen,
+            image = excluded.image,
+            name = coalesce(excluded.name, name),
+            set_code = coalesce(excluded.set_code, set_code),
+            source = coalesce(excluded.source, source),
+            source_url = coalesce(excluded.source_url, source_url),
+            known = coalesce(excluded.known, known)",
+        params![
+            spoiler.key(),
+            now,
+            spoiler.source_site_url.host_str(),
+            spoiler.source_site_url.as_str(),
+            canonical(&spoiler.image).as_str(),
+            spoiler.name,
+            spoiler.set_code,
+            spoiler.source.as_ref().map(|s| &s.name),
+            spoiler
+                .source
+                .as_ref()
+                .and_then(|s| s.url.as_ref())
+                .map(Url::as_str),
+            known,
+        ],
+    )?;
+    Ok(())
+}
+
+fn unix(time: SystemTime) -> i64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+fn record(row: &Row<'_>) -> rusqlite::Result<Record> {
+    let time = |i| {
+        row.get::<_, i64>(i)
+            .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64))
+    };
+    let url = |i| {
+        let url = row.get::<_, String>(i)?;
+        Url::parse(&url)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(i, Type::Text, e.into()))
+    };
+    let source_url = match row.get::<_, Option<String>>(9)? {
+        Some(_) => Some(url(9)?),
+        None => None,
+    };
+    Ok(Record {
+        key: row.get(0)?,
+        first_seen: time(1)?,
+        last_seen: time(2)?,
+        site: row.get(3)?,
+        page: url(4)?,
+        image: url(5)?,
+        name: row.get(6)?,
+        set_code: row.get(7)?,
+        source: row.get::<_, Option<String>>(8)?.map(|name| SpoilerSource {
+            name,
+            url: source_url,
+        }),
+    })
+}
+
+/// Writes every staged change in a single transaction.
+fn write(connection: &mut Connection, staged: &HashMap<String, Staged>) -> Result<(), Error> {
+    let now = unix(SystemTime::now());
+    let transaction = connection.transaction()?;
+    for (key, staged) in staged {
+        match staged {
+            Staged::Listed(at) => {
+                transaction.execute(
+                    "UPDATE cards SET last_seen = max(last_seen, ?2) WHERE key = ?1",
+                    params![key, at],
+                )?;
+            }
+            Staged::Seen(spoiler) => save(&transaction, spoiler, None, now)?,
+            Staged::Known(known) => save(&transaction, &known.spoiler, Some(known), now)?,
+            Staged::Forgotten => {
+                transaction.execute("DELETE FROM cards WHERE key = ?1", [key])?;
+            }
+        }
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+impl Cache for Sqlite {
+    async fn filter_new(&mut self, spoilers: &[Spoiler]) -> Result<Vec<Spoiler>, Error> {
+        let now = unix(SystemTime::now());
+        let unstaged = spoilers
+            .iter()
+            .map(Spoiler::key)
+            .filter(|key| !self.staged.contains_key(key))
+            .collect::<Vec<_>>();
+        let saved = self
+            .blocking(move |connection| {
+                let mut saved = Vec::new();
+                for key in unstaged {
+                    if contains(connection, &key)? {
+                        saved.push(key);
+                    }
+                }
+                Ok::<_, Error>(saved)
+            })
+            .await?;
+        for key in saved {
+            self.staged.insert(key, Staged::Listed(now));
+        }
+        Ok(spoilers
+            .iter()
+            .filter(|spoiler| match self.staged.get(&spoiler.key()) {
+                Some(Staged::Forgotten) | None => true,
+                Some(_) => false,
+            })
+            .cloned()
+            .collect())
+    }
+    async fn mark_seen(&mut self, spoilers: &[Spoiler]) -> Result<(), Error> {
+        for spoiler in spoilers {
+            let key = spoiler.key();
+            // what's known of it says it's been seen already
+            if !matches!(self.staged.get(&key), Some(Staged::Known(_))) {
+                self.staged
+                    .insert(key, Staged::Seen(Box::new(spoiler.clone())));
+            }
+        }
+        Ok(())
+    }
+
+    async fn forget(&mut self, spoilers: &[Spoiler]) -> Result<(), Error> {
+        for spoiler in spoilers {
+            self.staged.insert(spoiler.key(), Staged::Forgotten);
+        }
+        Ok(())
+    }
+
+    async fn known(&mut self, spoilers: &[Spoiler]) -> Result<Vec<Option<Known>>, Error> {
+        if !self.updates {
+            return Ok(vec![None; spoilers.len()]);
+        }
+        // what's staged, or the key to load it from the database with
+        let known = spoilers
+            .iter()
+            .map(|spoiler| {
+                let key = spoiler.key();
+                match self.staged.get(&key) {
+                    Some(Staged::Known(known)) => Ok(Some(Known::clone(known))),
+                    Some(Staged::Forgotten) => Ok(None),
+                    Some(Staged::Listed(_) | Staged::Seen(_)) | None => Err(key),
+                }
+            })
+            .collect::<Vec<_>>();
+        self.blocking(move |connection| {
+            known
+                .into_iter()
+                .map(|known| known.or_else(|key| load_known(connection, &key)))
+                .collect()
+        })
+        .await
+    }
+
+    async fn remember(&mut self, known: &[Known]) -> Result<(), Error> {
+        for known in known {
+            self.staged
+                .insert(known.spoiler.key(), Staged::Known(Box::new(known.clone())));
+        }
+        Ok(())
+    }
+
+    /// Writes every staged change in a single transaction.
+    async fn commit(&mut self) -> Result<(), Error> {
+        let staged = std::mem::take(&mut self.staged);
+        let (staged, written) = self
+            .blocking(move |connection| {
+                let written = write(connection, &staged);
+                (staged, written)
+            })
+            .await;
+        // kept for another try, or a rollback
+        if written.is_err() {
+            self.staged = staged;
+        }
+        written
+    }
+
+    async fn rollback(&mut self) -> Result<(), Error> {
+        self.staged.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::{
+        self,
+        test::{is_new, spoiler},
+    };
+
+    #[tokio::test]
+    async fn conformance() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.db");
+        cache::test::conformance(|updates| {
+            let cache = Sqlite::new(&path).unwrap().updates(updates);
+            async { cache }
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn waiting_for_the_lock_leaves_the_runtime_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.db");
+        let mut cache = Sqlite::new(&path).unwrap();
+        let other = Connection::open(&path).unwrap();
+        other.execute_batch("BEGIN IMMEDIATE").unwrap();
+
+        cache
+            .mark_seen(&[spoiler("gingerbreadhunter")])
+            .await
+            .unwrap();
+        let commit = tokio::spawn(async move { cache.commit().await });
+        // the test's runtime has a single thread, which the commit mustn't hold on to
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!commit.is_finished());
+        other.execute_batch("COMMIT").unwrap();
+        commit.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn cards_seen_since() {
+        let mut cache = Sqlite::new(":memory:").unwrap();
+        let hunter = spoiler("gingerbreadhunter");
+        cache
+            .mark_seen(&[hunter.clone(), spoiler("ragingfirebolt")])
+            .await
+            .unwrap();
+        cache.commit().await.unwrap();
+        let two_days_ago = unix(SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60));
+        cache
+            .connection
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE cards SET first_seen = ?1 WHERE key = 'woe/gingerbreadhunter'",
+                [two_days_ago],
+            )
+            .unwrap();
+        // seeing it again doesn't make it new
+        cache.mark_seen(&[hunter]).await.unwrap();
+        cache.commit().await.unwrap();
+
+        let day_ago = SystemTime::now() - Duration::from_secs(24 * 60 * 60);
+        let recent = cache.seen_since(day_ago).unwrap();
+        assert_eq!(recent.len(), 1);
+        let firebolt = &recent[0];
+        assert_eq!(firebolt.key, "woe/ragingfirebolt");
+        assert_eq!(firebolt.site.as_deref(), Some("mythicspoiler.com"));
+        assert_eq!(firebolt.set_code.as_deref(), Some("woe"));
+        assert_eq!(firebolt.source.as_ref().unwrap().name, "WeeklyMTG");
+        assert_eq!(firebolt.page, spoiler("ragingfirebolt").source_site_url);
+        assert_eq!(cache.seen_since(SystemTime::UNIX_EPOCH).unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn listing_is_staged_until_commit() {
+        let mut cache = Sqlite::new(":memory:").unwrap();
+        let hunter = spoiler("gingerbreadhunter");
+        cache
+            .mark_seen(std::slice::from_ref(&hunter))
+            .await
+            .unwrap();
+        cache.commit().await.unwrap();
+        cache
+            .connection
+            .lock()
+            .unwrap()
+            .execute("UPDATE cards SET last_seen = 0", [])
+            .unwrap();
+        let last_seen =
+            |cache: &Sqlite| cache.seen_since(SystemTime::UNIX_EPOCH).unwrap()[0].last_seen;
+
+        assert!(!is_new(&mut cache, &hunter).await);
+        cache.rollback().await.unwrap();
+        assert_eq!(last_seen(&cache), SystemTime::UNIX_EPOCH);
+
+        assert!(!is_new(&mut cache, &hunter).await);
+        cache.commit().await.unwrap();
+        assert!(last_seen(&cache) > SystemTime::UNIX_EPOCH);
+    }
+}
@@ -0,0 +1,390 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::CardText;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Color {
+    White,
+    Blue,
+    Black,
+    Red,
+    Green,
+}
+
+impl Color {
+    fn from_symbol(c: char) -> Option<Self> {
+        match c.to_ascii_uppercase() {
+            'W' => Some(Self::White),
+            'U' => Some(Self::Blue),
+            'B' => Some(Self::Black),
+            'R' => Some(Self::Red),
+            'G' => Some(Self::Green),
+            _ => None,
+        }
+    }
+}
+
+/// A mana cost, as a list of symbols without the braces, e.g. `["4", "B", "B/P"]`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ManaCost {
+    pub symbols: Vec<String>,
+}
+
+impl ManaCost {
+    /// Parses both the braced (`{4}{B}{B/P}`) and the compact (`4BB/P`) notations.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let symbols = if s.contains('{') {
+            s.split(['{', '}'])
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_uppercase)
+                .collect::<Vec<_>>()
+        } else {
+            let mut symbols = Vec::<String>::new();
+            let mut chars = s.chars().filter(|c| !c.is_whitespace()).peekable();
+            while let Some(c) = chars.next() {
+                if c.is_ascii_digit() {
+                    let mut generic = c.to_string();
+                    while let Some(d) = chars.next_if(char::is_ascii_digit) {
+                        generic.push(d);
+                    }
+                    symbols.push(generic);
+                } else if c == '/' {
+                    let last = symbols.last_mut()?;
+                    last.push('/');
+                    last.push(chars.next()?.to_ascii_uppercase());
+                } else if "WUBRGCXYZS".contains(c.to_ascii_uppercase()) {
+                    symbols.push(c.to_ascii_uppercase().to_string());
+                } else {
+                    return None;
+                }
+            }
+            symbols
+        };
+        (!symbols.is_empty()).then_some(Self { symbols })
+    }
+
+    /// The colors of the symbols in the cost, hybrid ones count as each of theirs.
+    pub fn colors(&self) -> Vec<Color> {
+        let mut colors = self
+            .symbols
+            .iter()
+            .flat_map(|s| s.chars())
+            .filter_map(Color::from_symbol)
+            .collect::<Vec<_>>();
+        colors.sort();
+        colors.dedup();
+        colors
+    }
+
+    /// The total amount of mana this costs, `X` counts as zero.
+    pub fn mana_value(&self) -> u32 {
+        self.symbols
+            .iter()
+            .map(|s| match s.parse::<u32>() {
+                Ok(generic) => generic,
+                Err(_) if matches!(&s[..], "X" | "Y" | "Z") => 0,
+                Err(_) => s
+                    .split('/')
+                    .filter_map(|s| s.parse::<u32>().ok())
+                    .max()
+                    .unwrap_or(1),
+            })
+            .sum()
+    }
+}
+
+impl fmt::Display for ManaCost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.symbols.iter().try_for_each(|s| write!(f, "{{{s}}}"))
+    }
+}
+
+/// A type line split into its parts, e.g. `Legendary Planeswalker - Vraska`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TypeLine {
+    pub supertypes: Vec<String>,
+    pub types: Vec<String>,
+    pub subtypes: Vec<String>,
+}
+
+impl TypeLine {
+    const SUPERTYPES: &'static [&'static str] =
+        &["Basic", "Legendary", "Ongoing", "Snow", "World", "Host"];
+    const TYPES: &'static [&'static str] = &[
+        "Artifact",
+        "Battle",
+        "Conspiracy",
+        "Creature",
+        "Dungeon",
+        "Enchantment",
+        "Instant",
+        "Kindred",
+        "Land",
+        "Phenomenon",
+        "Plane",
+        "Planeswalker",
+        "Scheme",
+        "Sorcery",
+        "Tribal",
+        "Vanguard",
+    ];
+
+    /// Words are classified by what they are rather than by which side of the dash they are on,
+    /// since sites don't always agree on the order (mythic writes `Adventure - Instant`). Only
+    /// the dash between spaces separates them, subtypes like `Assembly-Worker` have one too.
+    pub fn parse(s: &str) -> Self {
+        let mut type_line = Self::default();
+        let (types, subtypes) = [" - ", " — ", " – "]
+            .iter()
+            .find_map(|dash| s.split_once(dash))
+            .unwrap_or((s, ""));
+        for word in types.split_whitespace().chain(subtypes.split_whitespace()) {
+            let word = word.to_owned();
+            if Self::SUPERTYPES.contains(&&word[..]) {
+                type_line.supertypes.push(word);
+            } else if Self::TYPES.contains(&&word[..]) {
+                type_line.types.push(word);
+            } else {
+                type_line.subtypes.push(word);
+            }
+        }
+        type_line
+    }
+
+    pub fn is(&self, ty: &str) -> bool {
+        self.types
+            .iter()
+            .chain(&self.supertypes)
+            .chain(&self.subtypes)
+            .any(|t| t.eq_ignore_ascii_case(ty))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Rarity {
+    Common,
+    Uncommon,
+    Rare,
+    Mythic,
+    Special,
+    Bonus,
+}
+
+impl Rarity {
+    pub fn parse(s: &str) -> Option<Self> {
+        match &s.trim().to_lowercase()[..] {
+            "c" | "common" => Some(Self::Common),
+            "u" | "uncommon" => Some(Self::Uncommon),
+            "r" | "rare" => Some(Self::Rare),
+            "m" | "mythic" | "mythic rare" => Some(Self::Mythic),
+            "s" | "special" => Some(Self::Special),
+            "b" | "bonus" => Some(Self::Bonus),
+            _ => None,
+        }
+    }
+}
+
+/// How the faces of a card fit together.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Layout {
+    #[default]
+    Normal,
+    /// A creature or permanent with an instant or sorcery adventure attached to it.
+    Adventure,
+    /// Several spells printed side by side on the front, including aftermath cards and rooms.
+    Split,
+    /// A double faced card where either face can be cast.
+    ModalDfc,
+    /// A double faced card that starts on its front face and transforms into its back.
+    Transform,
+    /// A card with a second face printed upside down on the bottom half.
+    Flip,
+    /// A battle, which transforms into its back face when defeated.
+    Battle,
+}
+
+impl Layout {
+    /// Guesses the layout from the faces, sites don't say which one a card has.
+    ///
+    /// Transforming cards say so on their front face and their back faces have no mana cost,
+    /// while the back faces of split cards and modal double faced cards do, unless they are
+    /// lands. A missing cost only counts if the front has one, some sites don't give costs at
+    /// all. Split cards are the ones made of instants, sorceries or rooms, flip cards the ones
+    /// whose front says to flip it by name.
+    pub fn of(faces: &[CardText]) -> Self {
+        let [front, back, ..] = faces else {
+            return Self::Normal;
+        };
+        let is = |face: &CardText, ty: &str| face.types.as_ref().is_some_and(|t| t.is(ty));
+        let mentions = |face: &CardText, word: &str| {
+            face.text.as_deref().is_some_and(|text| {
+                text.split(|c: char| !c.is_alphanumeric())
+                    .any(|w| w.eq_ignore_ascii_case(word))
+            })
+        };
+        // flip cards say "flip {name}", other cards flip coins
+        let flips = |face: &CardText| {
+            let (Some(text), Some(name)) = (&face.text, &face.name) else {
+                return false;
+            };
+            let text = text.to_lowercase();
+            let name = name.to_lowercase();
+            text.match_indices("flip ")
+                .any(|(i, flip)| text[i + flip.len()..].starts_with(&name))
+        };
+
+        if faces.iter().any(|f| is(f, "Adventure")) {
+            Self::Adventure
+        } else if is(front, "Battle") {
+            Self::Battle
+        } else if flips(front) {
+            Self::Flip
+        } else if mentions(front, "transform")
+            || (front.mana_cost.is_some() && back.mana_cost.is_none() && !is(back, "Land"))
+        {
+            Self::Transform
+        } else if faces
+            .iter()
+            .all(|f| is(f, "Instant") || is(f, "Sorcery") || is(f, "Room"))
+        {
+            Self::Split
+        } else {
+            Self::ModalDfc
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mana_cost_notations() {
+        let compact = ManaCost::parse("4BB/P").unwrap();
+        let braced = ManaCost::parse("{4}{B}{b/p}").unwrap();
+        assert_eq!(compact, braced);
+        assert_eq!(compact.symbols, ["4", "B", "B/P"]);
+        assert_eq!(compact.to_string(), "{4}{B}{B/P}");
+        assert_eq!(compact.mana_value(), 6);
+        assert_eq!(compact.colors(), [Color::Black]);
+
+        let cost = ManaCost::parse("X1RG").unwrap();
+        assert_eq!(cost.mana_value(), 3);
+        assert_eq!(cost.colors(), [Color::Red, Color::Green]);
+
+        assert_eq!(ManaCost::parse(""), None);
+        assert_eq!(ManaCost::parse("Illus. Chase Stone"), None);
+    }
+
+    #[test]
+    fn type_lines() {
+        let vraska = TypeLine::parse("Legendary Planeswalker - Vraska");
+        assert_eq!(vraska.supertypes, ["Legendary"]);
+        assert_eq!(vraska.types, ["Planeswalker"]);
+        assert_eq!(vraska.subtypes, ["Vraska"]);
+
+        let adventure = TypeLine::parse("Adventure - Instant");
+        assert_eq!(adventure.types, ["Instant"]);
+        assert_eq!(adventure.subtypes, ["Adventure"]);
+        assert_eq!(adventure, TypeLine::parse("Instant — Adventure"));
+
+        let artifact_creature = TypeLine::parse("Artifact Creature - Golem Construct");
+        assert_eq!(artifact_creature.types, ["Artifact", "Creature"]);
+        assert_eq!(artifact_creature.subtypes, ["Golem", "Construct"]);
+        assert!(artifact_creature.is("creature"));
+
+        let worker = TypeLine::parse("Artifact Creature — Assembly-Worker");
+        assert_eq!(worker.types, ["Artifact", "Creature"]);
+        assert_eq!(worker.subtypes, ["Assembly-Worker"]);
+        assert_eq!(
+            worker,
+            TypeLine::parse("Artifact Creature - Assembly-Worker")
+        );
+    }
+
+    #[test]
+    fn missing_mana_costs_are_not_a_transform() {
+        let face = |type_line: &str, cost: &str| CardText {
+            types: Some(TypeLine::parse(type_line)),
+            mana_cost: ManaCost::parse(cost),
+            ..Default::default()
+        };
+        let kianne = [
+            face("Legendary Creature - Elf Druid", ""),
+            face("Legendary Creature - Bird Wizard", ""),
+        ];
+        assert_eq!(Layout::of(&kianne), Layout::ModalDfc);
+        let delver = [
+            face("Creature - Human Wizard", "U"),
+            face("Creature - Human Insect", ""),
+        ];
+        assert_eq!(Layout::of(&delver), Layout::Transform);
+    }
+
+    #[test]
+    fn flip_cards() {
+        let face = |name: &str, type_line: &str, cost: &str, text: &str| CardText {
+            name: Some(name.into()),
+            types: Some(TypeLine::parse(type_line)),
+            mana_cost: ManaCost::parse(cost),
+            text: Some(text.into()),
+            ..Default::default()
+        };
+        let bushi = [
+            face(
+                "Bushi Tenderfoot",
+                "Creature - Human Monk",
+                "1W",
+                "When that creature dies, flip Bushi Tenderfoot.",
+            ),
+            face(
+                "Kenzo the Hardhearted",
+                "Legendary Creature - Human Soldier",
+                "",
+                "Bushido 2",
+            ),
+        ];
+        assert_eq!(Layout::of(&bushi), Layout::Flip);
+        assert_eq!(Layout::of(&bushi[..1]), Layout::Normal);
+
+        let ral = [
+            face(
+                "Ral, Monsoon Mage",
+                "Legendary Creature - Human Wizard",
+                "1R",
+                "Whenever you cast an instant or sorcery spell during your turn, flip a coin. If you \
+                 lose the flip, Ral, Monsoon Mage deals 1 damage to you. If you win the flip, you \
+                 may exile Ral. If you do, return him to the battlefield transformed under his \
+                 owner's control.",
+            ),
+            face(
+                "Ral, Leyline Prodigy",
+                "Legendary Planeswalker - Ral",
+                "",
+                "Ral, Leyline Prodigy enters with an additional loyalty counter on him for each \
+                 instant and sorcery spell you've cast this turn.",
+            ),
+        ];
+        assert_eq!(Layout::of(&ral), Layout::Transform);
+
+        let room = [
+            face(
+                "Bottomless Pool",
+                "Enchantment - Room",
+                "1B",
+                "When you unlock this door, ...",
+            ),
+            face(
+                "Locker Room",
+                "Enchantment - Room",
+                "3B",
+                "At the beginning of your upkeep, ...",
+            ),
+        ];
+        assert_eq!(Layout::of(&room), Layout::Split);
+    }
+}
@@ -0,0 +1,114 @@
+use std::path::Path;
+
+use scraper::Selector;
+use serde::Deserialize;
+
+use crate::{
+    magic_spoiler::{self, MagicSpoiler},
+    mythic::{self, Mythic},
+    site::Registry,
+    Error,
+};
+
+/// The scraping rules of every site, so that a change in a site's markup can be worked around
+/// by editing a file instead of waiting for a new release.
+///
+/// Anything left out keeps its default, e.g.
+///
+/// ```toml
+/// [mythic]
+/// card = "div.card-grid"
+///
+/// [mythic.markers]
+/// text = "RULES TEXT"
+///
+/// [magic-spoiler]
+/// face = "div.card-face-details"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub mythic: mythic::Rules,
+    #[serde(rename = "magic-spoiler")]
+    pub magic_spoiler: magic_spoiler::Rules,
+}
+
+impl Config {
+    pub fn from_toml(s: &str) -> Result<Self, Error> {
+        toml::from_str(s).map_err(|e| Error::Config(e.to_string()))
+    }
+
+    pub async fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::from_toml(&tokio::fs::read_to_string(path).await?)
+    }
+
+    /// Every site of the default [`Registry`], scraping with these rules.
+    ///
+    /// Fails if any of the selectors is invalid.
+    pub fn registry(&self) -> Result<Registry, Error> {
+        let mut registry = Registry::empty();
+        registry
+            .register(Mythic::default().rules(self.mythic.clone())?)
+            .register(MagicSpoiler::default().rules(self.magic_spoiler.clone())?);
+        Ok(registry)
+    }
+}
+
+pub(crate) fn selector(s: &str) -> Result<Selector, Error> {
+    Selector::parse(s).map_err(|e| Error::Config(format!("invalid selector {s:?}: {e:?}")))
+}
+
+#[cfg(test)]
+mod test {
+    use reqwest::Url;
+
+    use super::*;
+    use crate::{site::SpoilerSite, transport::Fake};
+
+    #[test]
+    fn missing_rules_keep_their_defaults() {
+        let config = Config::from_toml(
+            r#"
+            [mythic]
+            card = "div.card-grid"
+
+            [mythic.markers]
+            text = "RULES TEXT"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mythic.card, "div.card-grid");
+        assert_eq!(config.mythic.markers.text, "RULES TEXT");
+        assert_eq!(config.mythic.markers.type_line, "TYPE");
+        assert_eq!(config.mythic.link, mythic::Rules::default().link);
+        assert_eq!(config.magic_spoiler, magic_spoiler::Rules::default());
+        assert_eq!(Config::from_toml("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn bad_configs_are_errors() {
+        assert!(matches!(
+            Config::from_toml("[mythic]\ncrad = \"div\""),
+            Err(Error::Config(_))
+        ));
+        let config = Config::from_toml("[magic-spoiler]\ncard = \"div[\"").unwrap();
+        assert!(matches!(config.registry(), Err(Error::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn sites_scrape_with_the_configured_rules() {
+        let config = Config::from_toml("[mythic]\ncard = \"div.card-grid\"").unwrap();
+        let transport = Fake::new().with_page(
+            Url::parse("https://mythicspoiler.com/newspoilers.html").unwrap(),
+            r#"<div class="card-grid"><a href="woe/cards/x.html"><img src="woe/cards/x.jpg"></a></div>"#,
+        );
+        let spoilers = Mythic::new(transport)
+            .rules(config.mythic)
+            .unwrap()
+            .fetch_listing()
+            .await
+            .unwrap();
+        assert_eq!(spoilers.len(), 1);
+        assert_eq!(spoilers[0].set_code.as_deref(), Some("woe"));
+    }
+}
@@ -0,0 +1,57 @@
+//! Pages the parsers are tested against.
+//!
+//! Fixtures live in `assets/` and are read from disk, so the test suite never touches the
+//! network. Recorded fixtures are loaded with [`load`]: running the tests with
+//! `MTG_SPOILERS_RECORD=1` fetches each of them from its live url first and overwrites the
+//! recorded copy, which is how fixtures get refreshed when a site changes.
+//!
+//! Tests of pages that weren't recorded yet are ignored, until
+//! `MTG_SPOILERS_RECORD=1 cargo test -- --ignored` records them and their `ignore` goes.
+//!
+//! The pages under `assets/handwritten/` weren't saved from the sites, they're markup written
+//! to look like theirs, loaded with [`handwritten`]. They only stand in for a site behind a fake
+//! transport, the parsers are tested against recorded pages.
+
+use std::path::PathBuf;
+
+const RECORD: &str = "MTG_SPOILERS_RECORD";
+
+fn recording() -> bool {
+    std::env::var_os(RECORD).is_some_and(|v| !v.is_empty() && v != "0")
+}
+
+/// Loads the recorded `assets/{fixture}`, re-recording it from `url` first if recording is
+/// enabled.
+pub async fn load(fixture: &str, url: &str) -> String {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("assets")
+        .join(fixture);
+    if recording() {
+        let page = reqwest::get(url)
+            .await
+            .and_then(|r| r.error_for_status())
+            .unwrap_or_else(|e| panic!("failed to record {url}: {e}"));
+        let page = page
+            .text()
+            .await
+            .unwrap_or_else(|e| panic!("failed to record {url}: {e}"));
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await.unwrap();
+        }
+        tokio::fs::write(&path, &page).await.unwrap();
+        return page;
+    }
+    tokio::fs::read_to_string(&path).await.unwrap_or_else(|e| {
+        panic!("failed to read fixture {path:?}: {e}, run the tests with {RECORD}=1 to record it")
+    })
+}
+
+/// Loads `assets/handwritten/{fixture}`, which is never recorded.
+pub async fn handwritten(fixture: &str) -> String {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("assets/handwritten")
+        .join(fixture);
+    tokio::fs::read_to_string(&path)
+        .await
+        .unwrap_or_else(|e| panic!("failed to read fixture {path:?}: {e}"))
+}
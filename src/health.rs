@@ -0,0 +1,234 @@
+use std::fmt;
+
+use futures::StreamExt;
+
+use crate::{
+    site::{self, SpoilerSite},
+    Error,
+};
+
+/// How well a site's scraper is doing, measured on whatever its transport serves, the live site
+/// or a recorded page.
+///
+/// Markup changes rarely break a scraper outright, they make it quietly find less, so this
+/// measures how much of what it used to find it still finds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub site: &'static str,
+    /// Cards in the listing.
+    pub cards: usize,
+    /// How many of them were resolved and had their text fetched, the newest ones.
+    pub sampled: usize,
+    /// Fraction of the sampled cards with a name, once resolved.
+    pub with_names: f64,
+    /// Fraction of the listing's cards that say who spoiled them.
+    pub with_sources: f64,
+    /// Fraction of the sampled cards whose page has some text on its faces.
+    pub with_text: f64,
+}
+
+/// The worst a [`Report`] can be before [`Report::check`] fails.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thresholds {
+    pub min_cards: usize,
+    pub with_names: f64,
+    pub with_sources: f64,
+    pub with_text: f64,
+}
+
+impl Default for Thresholds {
+    /// At least one card, half of them with names and text. Sources are optional, plenty of
+    /// spoilers don't credit anyone.
+    fn default() -> Self {
+        Self {
+            min_cards: 1,
+            with_names: 0.5,
+            with_sources: 0.,
+            with_text: 0.5,
+        }
+    }
+}
+
+/// Fetches `site`'s listing and the pages of its `sample` newest cards, and measures what the
+/// parsers got out of them.
+#[tracing::instrument(skip_all, fields(site = site.name()))]
+pub async fn report(site: &dyn SpoilerSite, sample: usize) -> Result<Report, Error> {
+    let listing = site.fetch_listing().await?;
+    let with_sources = listing.iter().filter(|s| s.source.is_some()).count();
+
+    let sampled = futures::stream::iter(listing.iter().take(sample).cloned())
+        .map(|mut spoiler| async move {
+            let (resolution, card) = site.resolve_with_text(&mut spoiler).await;
+            let has_text = match card {
+                Some(Ok(card)) => card.has_text(),
+                Some(Err(e)) => {
+                    tracing::debug!(url = %spoiler.source_site_url, ?e, "no card text");
+                    false
+                }
+                None => {
+                    tracing::debug!(url = %spoiler.source_site_url, ?resolution, "no card page");
+                    false
+                }
+            };
+            (spoiler.name.is_some(), has_text)
+        })
+        .buffer_unordered(site::CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+
+    let fraction = |n: usize, of: usize| if of == 0 { 0. } else { n as f64 / of as f64 };
+    Ok(Report {
+        site: site.name(),
+        cards: listing.len(),
+        sampled: sampled.len(),
+        with_names: fraction(
+            sampled.iter().filter(|(name, _)| *name).count(),
+            sampled.len(),
+        ),
+        with_sources: fraction(with_sources, listing.len()),
+        with_text: fraction(
+            sampled.iter().filter(|(_, text)| *text).count(),
+            sampled.len(),
+        ),
+    })
+}
+
+/// [`report`]s on `site` and [`check`](Report::check)s it against `thresholds`.
+pub async fn check(
+    site: &dyn SpoilerSite,
+    sample: usize,
+    thresholds: &Thresholds,
+) -> Result<Report, Error> {
+    let report = report(site, sample).await?;
+    report.check(thresholds)?;
+    Ok(report)
+}
+
+impl Report {
+    /// Fails with [`Error::Unhealthy`] listing every metric below its threshold.
+    pub fn check(&self, thresholds: &Thresholds) -> Result<(), Error> {
+        let mut problems = Vec::new();
+        if self.cards < thresholds.min_cards {
+            problems.push(format!(
+                "{} cards, expected at least {}",
+                self.cards, thresholds.min_cards
+            ));
+        }
+        let mut below = |what: &str, value: f64, threshold: f64| {
+            if value < threshold {
+                problems.push(format!(
+                    "{:.0}% {what}, expected at least {:.0}%",
+                    value * 100.,
+                    threshold * 100.
+                ));
+            }
+        };
+        // there is nothing to measure these on without cards, which is already reported
+        if self.sampled > 0 {
+            below("with names", self.with_names, thresholds.with_names);
+            below("with text", self.with_text, thresholds.with_text);
+        }
+        if self.cards > 0 {
+            below("with sources", self.with_sources, thresholds.with_sources);
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Unhealthy {
+                site: self.site,
+                problems,
+            })
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} cards, {:.0}% with sources, of {} sampled {:.0}% with names and {:.0}% with text",
+            self.site,
+            self.cards,
+            self.with_sources * 100.,
+            self.sampled,
+            self.with_names * 100.,
+            self.with_text * 100.,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use reqwest::Url;
+
+    use super::*;
+    use crate::{fixtures, mythic::Mythic, site::Registry, transport::Fake};
+
+    #[tokio::test]
+    #[ignore = "hits the live site"]
+    async fn live_sites_are_healthy() {
+        for site in Registry::default().iter() {
+            let report = check(site.as_ref(), 10, &Thresholds::default())
+                .await
+                .unwrap();
+            println!("{report}");
+        }
+    }
+
+    async fn mythic() -> Mythic {
+        let url = |s: &str| Url::parse(&format!("https://mythicspoiler.com/{s}")).unwrap();
+        let mut transport = Fake::new().with_page(
+            url("newspoilers.html"),
+            r#"
+            <div class="grid-card"><a href="woe/cards/gingerbreadhunter.html"><img src="woe/cards/gingerbreadhunter.jpg"></a><center><a href="twitch.tv/magic"><font>WeeklyMTG</font></a></center></div>
+            <div class="grid-card"><a href="woe/cards/ragingfirebolt.html"><img src="woe/cards/ragingfirebolt.jpg"></a></div>
+            <div class="grid-card"><a href="woe/cards/picklockprankster.html"><img src="woe/cards/picklockprankster.jpg"></a></div>
+            <div class="grid-card"><a href="woe/cards/notsampled.html"><img src="woe/cards/notsampled.jpg"></a></div>
+            "#,
+        );
+        for card in ["gingerbreadhunter", "ragingfirebolt", "picklockprankster"] {
+            transport = transport.with_page(
+                url(&format!("woe/cards/{card}.html")),
+                fixtures::handwritten(&format!("mythic/woe/{card}.html")).await,
+            );
+        }
+        Mythic::new(transport)
+    }
+
+    #[tokio::test]
+    async fn measures_fixture_pages() {
+        let report = report(&mythic().await, 3).await.unwrap();
+        assert_eq!(report.cards, 4);
+        assert_eq!(report.sampled, 3);
+        // the prankster's page wasn't filled in yet
+        assert!((report.with_names - 2. / 3.).abs() < 1e-9);
+        assert!((report.with_text - 2. / 3.).abs() < 1e-9);
+        assert_eq!(report.with_sources, 0.25);
+        report.check(&Thresholds::default()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn thresholds_turn_into_errors() {
+        let report = report(&mythic().await, 3).await.unwrap();
+        let strict = Thresholds {
+            min_cards: 10,
+            with_names: 0.9,
+            with_sources: 0.5,
+            with_text: 0.5,
+        };
+        match report.check(&strict) {
+            Err(Error::Unhealthy { site, problems }) => {
+                assert_eq!(site, "mythic");
+                assert_eq!(
+                    problems,
+                    [
+                        "4 cards, expected at least 10",
+                        "67% with names, expected at least 90%",
+                        "25% with sources, expected at least 50%",
+                    ]
+                );
+            }
+            other => panic!("expected the report to be unhealthy, got {other:?}"),
+        }
+    }
+}
@@ -1,33 +1,166 @@
-use std::{io, sync::OnceLock};
+use std::io;
 
+use card::{Color, Layout, ManaCost, Rarity, TypeLine};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+pub mod aggregate;
 pub mod cache;
+pub mod card;
+pub mod config;
+#[cfg(test)]
+mod fixtures;
+pub mod health;
 pub mod magic_spoiler;
 pub mod mythic;
+pub mod site;
+pub mod transport;
+pub mod watch;
 
-static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
-fn http() -> &'static reqwest::Client {
-    CLIENT.get_or_init(Default::default)
-}
-
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct SpoilerSource {
     pub name: String,
-    pub url: Option<String>,
+    pub url: Option<Url>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Spoiler {
     pub name: Option<String>,
-    pub source_site_url: String,
-    pub image: String,
+    /// The card's page on the site.
+    pub source_site_url: Url,
+    pub image: Url,
     pub source: Option<SpoilerSource>,
+    /// Code of the set the card is from, lowercase, e.g. `"woe"`.
+    pub set_code: Option<String>,
+}
+
+impl Spoiler {
+    /// What identifies the card on its site however often its image is replaced: its set code
+    /// and the slug of its page, e.g. `"woe/gingerbreadhunter"`, or its name if the page has no
+    /// slug.
+    pub fn key(&self) -> String {
+        let name = slug(&self.source_site_url)
+            .map(normalize)
+            .filter(|s| !s.is_empty())
+            .or_else(|| self.name.as_deref().map(normalize))
+            .unwrap_or_else(|| canonical(&self.source_site_url).into());
+        match &self.set_code {
+            Some(set) => format!("{}/{name}", set.to_lowercase()),
+            None => name,
+        }
+    }
+}
+
+/// The last part of `url`'s path without its extension, e.g. `gingerbreadhunter` for
+/// `woe/cards/gingerbreadhunter.html`.
+pub(crate) fn slug(url: &Url) -> Option<&str> {
+    let last = url.path_segments()?.rfind(|s| !s.is_empty())?;
+    Some(last.split('.').next().unwrap_or(last))
+}
+
+/// Lowercase letters and digits only, so that `Vraska, Betrayal's Sting` and
+/// `vraska-betrayals-sting` agree.
+pub(crate) fn normalize(s: &str) -> String {
+    s.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// A set, as listed by a spoiler site.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Set {
+    /// Lowercase set code, e.g. `"woe"`.
+    pub code: String,
+    pub name: String,
+    /// Page with every spoiler of the set.
+    pub url: Url,
+}
+
+/// A card's text, as parsed from its page.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Card {
+    pub layout: Layout,
+    /// Every face of the card, front first. Empty if the page has no text yet.
+    pub faces: Vec<CardText>,
+}
+
+impl Card {
+    pub fn from_faces(faces: Vec<CardText>) -> Self {
+        Self {
+            layout: Layout::of(&faces),
+            faces,
+        }
+    }
+
+    /// Whether any face says more than the card's name, pages are often up before their text.
+    pub fn has_text(&self) -> bool {
+        self.faces
+            .iter()
+            .any(|f| f.type_line.is_some() || f.text.is_some())
+    }
 }
 
-#[derive(Debug, Default, PartialEq, Eq)]
+/// One face of a card.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct CardText {
     pub name: Option<String>,
     pub type_line: Option<String>,
     pub text: Option<String>,
+    pub mana_cost: Option<ManaCost>,
+    /// [`type_line`](Self::type_line), parsed.
+    pub types: Option<TypeLine>,
+    pub power: Option<String>,
+    pub toughness: Option<String>,
+    pub loyalty: Option<String>,
+    pub defense: Option<String>,
+    pub rarity: Option<Rarity>,
+    pub set_code: Option<String>,
+    pub collector_number: Option<String>,
+}
+
+impl CardText {
+    /// The colors of the face's mana cost.
+    ///
+    /// Color indicators aren't parsed, so faces colored by one, like most back faces (Insectile
+    /// Aberration is blue), come out colorless.
+    pub fn colors(&self) -> Vec<Color> {
+        self.mana_cost
+            .as_ref()
+            .map(ManaCost::colors)
+            .unwrap_or_default()
+    }
+}
+
+/// The form of `url` used to tell whether two urls point to the same thing.
+///
+/// Sites switch between http and https and aren't careful with their slashes, so this uses
+/// https and drops empty path segments and the fragment.
+pub fn canonical(url: &Url) -> Url {
+    let mut canonical = url.clone();
+    if canonical.scheme() == "http" {
+        // http and https are both special schemes, so this can't fail
+        let _ = canonical.set_scheme("https");
+    }
+    let mut path = canonical.path().to_owned();
+    while path.contains("//") {
+        path = path.replace("//", "/");
+    }
+    canonical.set_path(&path);
+    canonical.set_fragment(None);
+    canonical
+}
+
+/// Resolves a link to somewhere off the site, which sites often write without a scheme, e.g.
+/// `twitch.tv/magic`. Those get https.
+fn external_link(href: &str) -> Option<Url> {
+    let href = href.trim();
+    if href.is_empty() {
+        return None;
+    }
+    Url::parse(href)
+        .or_else(|_| Url::parse(&format!("https://{}", href.trim_start_matches('/'))))
+        .ok()
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -36,4 +169,103 @@ pub enum Error {
     Reqwest(#[from] reqwest::Error),
     #[error("Io({0})")]
     Io(#[from] io::Error),
+    #[error("Url({0})")]
+    Url(#[from] url::ParseError),
+    #[error("Unsupported({site} can't {operation})")]
+    Unsupported {
+        site: &'static str,
+        operation: &'static str,
+    },
+    #[error("Status({status} at {url})")]
+    Status {
+        url: url::Url,
+        status: reqwest::StatusCode,
+    },
+    /// The page has the expected layout but some of it couldn't be made sense of.
+    #[error("Parse({url}: {context})")]
+    Parse { url: url::Url, context: String },
+    /// The page doesn't look like it used to, the site probably changed its markup.
+    #[error("Layout({url}: {context})")]
+    Layout { url: url::Url, context: String },
+    #[error("CacheCorrupted({}:{line}: {context})", path.display())]
+    CacheCorrupted {
+        path: std::path::PathBuf,
+        line: usize,
+        context: String,
+    },
+    #[cfg(feature = "sqlite")]
+    #[error("Sqlite({0})")]
+    Sqlite(#[from] rusqlite::Error),
+    #[cfg(feature = "redis")]
+    #[error("Redis({0})")]
+    Redis(#[from] redis::RedisError),
+    #[error("Config({0})")]
+    Config(String),
+    #[error("Unhealthy({site}: {})", problems.join(", "))]
+    Unhealthy {
+        site: &'static str,
+        problems: Vec<String>,
+    },
+    #[error("DisallowedByRobots({0})")]
+    DisallowedByRobots(url::Url),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn canonical_urls() {
+        let url = |s: &str| Url::parse(s).unwrap();
+        let canon = url("https://mythicspoiler.com/woe/cards/x.jpg");
+        for same in [
+            "https://mythicspoiler.com/woe/cards/x.jpg",
+            "https://mythicspoiler.com/woe//cards/x.jpg",
+            "HTTPS://MythicSpoiler.com:443/woe/cards/x.jpg#top",
+        ] {
+            assert_eq!(canonical(&url(same)), canon, "{same}");
+        }
+        assert_ne!(
+            canonical(&url("https://mythicspoiler.com/woe/cards/x.jpg?v=2")),
+            canon
+        );
+    }
+
+    #[test]
+    fn keys_survive_new_images() {
+        let url = |s: &str| Url::parse(s).unwrap();
+        let mut spoiler = Spoiler {
+            name: None,
+            source_site_url: url("https://mythicspoiler.com/woe/cards/gingerbreadhunter.html"),
+            image: url("https://mythicspoiler.com/woe/cards/gingerbreadhunter.jpg"),
+            source: None,
+            set_code: Some("WOE".into()),
+        };
+        assert_eq!(spoiler.key(), "woe/gingerbreadhunter");
+        spoiler.image = url("https://mythicspoiler.com/woe/cards/gingerbreadhunter2.png");
+        spoiler.name = Some("Gingerbread Hunter".into());
+        assert_eq!(spoiler.key(), "woe/gingerbreadhunter");
+
+        let magic_spoiler = Spoiler {
+            name: Some("Gingerbread Hunter".into()),
+            source_site_url: url("https://www.magicspoiler.com/mtg-spoiler/gingerbread-hunter/"),
+            image: url("https://www.magicspoiler.com/wp-content/uploads/2023/08/x.jpg"),
+            source: None,
+            set_code: None,
+        };
+        assert_eq!(magic_spoiler.key(), "gingerbreadhunter");
+    }
+
+    #[test]
+    fn external_links_default_to_https() {
+        assert_eq!(
+            external_link("twitch.tv/magic").unwrap().as_str(),
+            "https://twitch.tv/magic"
+        );
+        assert_eq!(
+            external_link(" http://twitter.com/x ").unwrap().as_str(),
+            "http://twitter.com/x"
+        );
+        assert_eq!(external_link(""), None);
+    }
 }
@@ -1,9 +1,97 @@
-use super::Spoiler;
-use crate::{cache::Cache, CardText, Error};
+use std::sync::{Arc, OnceLock};
+
+use serde::Deserialize;
+
+use super::{external_link, Spoiler, SpoilerSource};
+use crate::{
+    cache::Cache,
+    card::{ManaCost, TypeLine},
+    config::selector,
+    site::{self, Resolved, SpoilerSite},
+    transport::{self, Response, Transport},
+    Card, CardText, Error,
+};
+use async_trait::async_trait;
 use reqwest::Url;
+use scraper::{ElementRef, Html, Selector};
+
+static BASE: &str = "https://www.magicspoiler.com/";
+
+fn based(s: &str) -> Result<Url, url::ParseError> {
+    Url::parse(BASE)?.join(s)
+}
+
+/// [magicspoiler.com](https://www.magicspoiler.com)
+#[derive(Clone)]
+pub struct MagicSpoiler {
+    transport: Arc<dyn Transport>,
+    parser: Arc<Parser>,
+}
+
+impl MagicSpoiler {
+    pub fn new<T: Transport + 'static>(transport: T) -> Self {
+        Self {
+            transport: Arc::new(transport),
+            parser: Parser::shared(),
+        }
+    }
+
+    /// Scrapes with `rules` instead of the default ones, fails if a selector is invalid.
+    pub fn rules(mut self, rules: Rules) -> Result<Self, Error> {
+        self.parser = Arc::new(Parser::new(rules)?);
+        Ok(self)
+    }
+}
 
-pub async fn new_cards<C: Cache + Send + 'static>(mut cache: C) -> Result<Vec<Spoiler>, Error> {
-    todo!()
+impl Default for MagicSpoiler {
+    fn default() -> Self {
+        Self {
+            transport: transport::shared(),
+            parser: Parser::shared(),
+        }
+    }
+}
+
+impl std::fmt::Debug for MagicSpoiler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MagicSpoiler").finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl SpoilerSite for MagicSpoiler {
+    fn name(&self) -> &'static str {
+        "magic-spoiler"
+    }
+
+    fn base_url(&self) -> Url {
+        Url::parse(BASE).unwrap()
+    }
+
+    async fn fetch_listing(&self) -> Result<Vec<Spoiler>, Error> {
+        tracing::trace!("requesting page");
+        let response = request_page(&*self.transport).await?;
+        tracing::trace!("parsing document");
+        let doc = Html::parse_document(&response.body);
+        let found = doc.select(&self.parser.card).count();
+        site::check_listing(
+            &response,
+            &self.parser.card_rule,
+            found,
+            self.parser.parse_document(&response.url, &doc).collect(),
+        )
+    }
+
+    async fn fetch_card_text(&self, url: Url) -> Result<Card, Error> {
+        let doc = self.transport.get(url).await?.error_for_status()?.body;
+        Ok(Card::from_faces(
+            self.parser.parse_card_text(&Html::parse_document(&doc)),
+        ))
+    }
+}
+
+pub async fn new_cards<C: Cache + Send + 'static>(cache: C) -> Result<Vec<Resolved>, Error> {
+    site::new_cards(&MagicSpoiler::default(), cache).await
 }
 
 #[allow(dead_code)]
@@ -12,31 +100,284 @@ fn _assert() {
     is_send(new_cards(super::cache::empty::Empty));
 }
 
-pub async fn get_card_text(_url: Url) -> Result<Vec<CardText>, Error> {
-    todo!()
+async fn request_page(transport: &dyn Transport) -> Result<Response, Error> {
+    transport
+        .get(based("mtg-spoiler/")?)
+        .await?
+        .error_for_status()
+}
+
+/// The selectors the scraper looks for, see [`MagicSpoiler::rules`].
+///
+/// Every field defaults to what the site uses, so a config only needs the ones that changed.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rules {
+    /// A card in a listing.
+    pub card: String,
+    /// The link to the card's page, inside a card, its title is the card's name.
+    pub link: String,
+    /// The card's image, inside its link, its alt text is the card's name.
+    pub image: String,
+    /// The link to who spoiled the card, inside a card.
+    pub source: String,
+    /// The code of the card's set, inside a card.
+    pub set: String,
+    /// A face of the card, on its page.
+    pub face: String,
+    /// The name, mana cost, type line and rules text of a face, inside it.
+    pub name: String,
+    pub mana_cost: String,
+    pub type_line: String,
+    pub text: String,
+    /// The paragraphs of the rules text, inside it.
+    pub paragraph: String,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            card: "article.spoiler-set-card".into(),
+            link: "a".into(),
+            image: "img".into(),
+            source: "div.spoiler-source a".into(),
+            set: ".spoiler-set-code".into(),
+            face: "div.card-details div.card-face".into(),
+            name: ".card-name".into(),
+            mana_cost: ".card-cost".into(),
+            type_line: ".card-type".into(),
+            text: ".card-text".into(),
+            paragraph: "p".into(),
+        }
+    }
+}
+
+/// [`Rules`] with their selectors parsed.
+struct Parser {
+    card_rule: String,
+    card: Selector,
+    link: Selector,
+    image: Selector,
+    source: Selector,
+    set: Selector,
+    face: Selector,
+    name: Selector,
+    mana_cost: Selector,
+    type_line: Selector,
+    text: Selector,
+    paragraph: Selector,
+}
+
+impl Parser {
+    fn new(rules: Rules) -> Result<Self, Error> {
+        Ok(Self {
+            card: selector(&rules.card)?,
+            link: selector(&rules.link)?,
+            image: selector(&rules.image)?,
+            source: selector(&rules.source)?,
+            set: selector(&rules.set)?,
+            face: selector(&rules.face)?,
+            name: selector(&rules.name)?,
+            mana_cost: selector(&rules.mana_cost)?,
+            type_line: selector(&rules.type_line)?,
+            text: selector(&rules.text)?,
+            paragraph: selector(&rules.paragraph)?,
+            card_rule: rules.card,
+        })
+    }
+
+    /// The parser for the default rules.
+    fn shared() -> Arc<Self> {
+        static PARSER: OnceLock<Arc<Parser>> = OnceLock::new();
+        PARSER
+            .get_or_init(|| Arc::new(Self::new(Rules::default()).unwrap()))
+            .clone()
+    }
+
+    /// Every card on the page at `page`, the urls in it are relative to it.
+    fn parse_document<'a>(
+        &'a self,
+        page: &'a Url,
+        doc: &'a Html,
+    ) -> impl Iterator<Item = Spoiler> + 'a {
+        doc.select(&self.card)
+            .filter_map(|card| self.parse_card(page, card))
+    }
+
+    fn parse_card(&self, page: &Url, card: ElementRef<'_>) -> Option<Spoiler> {
+        let (link, img, source) = (&self.link, &self.image, &self.source);
+        /*
+         * <article class="spoiler-set-card">
+         *  <a href="https://www.magicspoiler.com/mtg-spoiler/gingerbread-hunter/" title="Gingerbread Hunter">
+         *      <img class="spoiler-card-img" src="https://www.magicspoiler.com/wp-content/uploads/2023/08/gingerbread-hunter.jpg" alt="Gingerbread Hunter">
+         *  </a>
+         *  <div class="spoiler-source">
+         *      Source: <a href="https://twitter.com/wizards_magic">Wizards of the Coast</a>
+         *  </div>
+         *  <span class="spoiler-set-code">WOE</span>
+         * </article>
+         */
+        let card_link = card.select(link).next()?;
+        let card_url = card_link.value().attr("href")?.trim();
+        let img = card_link.select(img).next()?;
+        let img_src = img.value().attr("src")?.trim();
+        let name = card_link
+            .value()
+            .attr("title")
+            .or_else(|| img.value().attr("alt"))
+            .map(str::trim)
+            .filter(|n| !n.is_empty())
+            .map(ToOwned::to_owned);
+        let source = card.select(source).next().and_then(|source| {
+            let source_name = source.text().collect::<String>();
+            let source_name = source_name.trim();
+            if source_name.is_empty() {
+                return None;
+            }
+            Some(SpoilerSource {
+                name: source_name.to_string(),
+                url: source.value().attr("href").and_then(external_link),
+            })
+        });
+
+        // the pages' urls don't say, and reprints would share a key without it
+        let set_code = card.select(&self.set).next().and_then(|set| {
+            let set = set.text().collect::<String>();
+            let set = set.trim();
+            (!set.is_empty()).then(|| set.to_lowercase())
+        });
+
+        Some(Spoiler {
+            image: page.join(img_src).ok()?,
+            source_site_url: page.join(card_url).ok()?,
+            name,
+            source,
+            set_code,
+        })
+    }
+
+    fn parse_card_text(&self, doc: &Html) -> Vec<CardText> {
+        let (face, name, mana_cost, type_line, text, paragraph) = (
+            &self.face,
+            &self.name,
+            &self.mana_cost,
+            &self.type_line,
+            &self.text,
+            &self.paragraph,
+        );
+        /*
+         * <div class="card-details">
+         *  <div class="card-face">
+         *      <h2 class="card-name">Gingerbread Hunter</h2>
+         *      <div class="card-cost">{4}{G}</div>
+         *      <div class="card-type">Creature — Giant</div>
+         *      <div class="card-text"><p>When Gingerbread Hunter enters ...</p></div>
+         *  </div>
+         *  <div class="card-face">...</div>
+         * </div>
+         */
+        fn trimmed_text(e: ElementRef<'_>) -> Option<String> {
+            let text = e
+                .text()
+                .collect::<String>()
+                .lines()
+                .map(str::trim)
+                .collect::<Vec<_>>()
+                .join("\n");
+            let text = text.trim();
+            (!text.is_empty()).then(|| text.to_owned())
+        }
+
+        doc.select(face)
+            .map(|face| {
+                let type_line = face
+                    .select(type_line)
+                    .next()
+                    .and_then(trimmed_text)
+                    .map(|t| t.replace(" — ", " - "));
+                CardText {
+                    name: face.select(name).next().and_then(trimmed_text),
+                    mana_cost: face
+                        .select(mana_cost)
+                        .next()
+                        .and_then(trimmed_text)
+                        .as_deref()
+                        .and_then(ManaCost::parse),
+                    types: type_line.as_deref().map(TypeLine::parse),
+                    type_line,
+                    text: face.select(text).next().and_then(|text| {
+                        let paragraphs = text
+                            .select(paragraph)
+                            .filter_map(trimmed_text)
+                            .collect::<Vec<_>>();
+                        if paragraphs.is_empty() {
+                            trimmed_text(text)
+                        } else {
+                            Some(paragraphs.join("\n\n"))
+                        }
+                    }),
+                    ..Default::default()
+                }
+            })
+            .filter(|t| *t != CardText::default())
+            .collect()
+    }
+}
+
+pub async fn get_card_text(url: Url) -> Result<Card, Error> {
+    MagicSpoiler::default().fetch_card_text(url).await
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::cache::empty::Empty;
+    use crate::{cache::empty::Empty, card::Layout, fixtures};
 
     #[tokio::test]
+    #[ignore = "hits the live site"]
     async fn foo() {
         let cards = new_cards(Empty).await.unwrap();
         assert_ne!(cards.len(), 0);
     }
 
+    #[tokio::test]
+    #[ignore = "needs a recording, see the fixtures module"]
+    async fn parse_new_spoilers() {
+        let page = based("mtg-spoiler/").unwrap();
+        let doc = fixtures::load("magic_spoiler/newspoilers.html", page.as_str()).await;
+        let doc = Html::parse_document(&doc);
+        let cards = Parser::shared()
+            .parse_document(&page, &doc)
+            .collect::<Vec<_>>();
+        assert!(!cards.is_empty());
+
+        // what's on the page changes with every recording, but not what each card is made of
+        for card in &cards {
+            assert!(card.name.is_some(), "{card:?}");
+            assert!(
+                card.source_site_url.as_str().starts_with(page.as_str()),
+                "{card:?}"
+            );
+            assert_eq!(card.image.host_str(), page.host_str(), "{card:?}");
+        }
+        assert!(cards.iter().any(|c| c.set_code.is_some()));
+        assert!(cards.iter().any(|c| c.source.is_some()));
+    }
+
     macro_rules! test_card_parser {
-        ($($exp:literal / $name:expr => [$({name: $e_name:expr, type_line: $e_type:expr, text: $e_text:expr}),*$(,)?])*) => {
+        ($($test:ident: $file:literal => $layout:ident [$({name: $e_name:expr, type_line: $e_type:expr, text: $e_text:expr}),*$(,)?])*) => {
             $(paste::paste! {
                 #[tokio::test]
-                async fn [<get_ $name>]() {
-                    let text = get_card_text(
-                        Url::parse(::std::concat!("https://mythicspoiler.com/", $exp, "/cards/", $name, ".html")).unwrap(),
+                #[ignore = "needs a recording, see the fixtures module"]
+                async fn [<get_ $test>]() {
+                    let doc = fixtures::load(
+                        ::std::concat!("magic_spoiler/", $file, ".html"),
+                        ::std::concat!("https://www.magicspoiler.com/mtg-spoiler/", $file, "/"),
                     )
-                    .await
-                    .unwrap();
+                    .await;
+                    let card = Card::from_faces(Parser::shared().parse_card_text(&Html::parse_document(&doc)));
+                    assert_eq!(card.layout, Layout::$layout);
+                    let text = card.faces;
 
                     let texts: [CardText; test_card_parser!(@count $($e_name),*)] = text.try_into().unwrap();
                     texts
@@ -60,50 +401,49 @@ mod test {
         };
     }
 
+    #[tokio::test]
+    #[ignore = "needs a recording, see the fixtures module"]
+    async fn parses_mana_costs() {
+        let doc = fixtures::load(
+            "magic_spoiler/revival-revenge.html",
+            "https://www.magicspoiler.com/mtg-spoiler/revival-revenge/",
+        )
+        .await;
+        let faces = Parser::shared().parse_card_text(&Html::parse_document(&doc));
+        let costs = faces
+            .iter()
+            .map(|f| f.mana_cost.as_ref().map(ToString::to_string))
+            .collect::<Vec<_>>();
+        assert_eq!(costs, [Some("{W/B}{W/B}".into()), Some("{4}{W}{B}".into())]);
+    }
+
     test_card_parser! {
-        "woe" / "gingerbreadhunter" => [
-            {name: None, type_line: "Creature - Giant", text: "When Gingerbread Hunter enters the battlefield, create a Food Token."},
-            {name: None, type_line: "Adventure - Instant", text: "Target creature gets -2/-2 until end of turn."},
+        gingerbread_hunter: "gingerbread-hunter" => Adventure [
+            {name: "Gingerbread Hunter", type_line: "Creature - Giant", text: "When Gingerbread Hunter enters the battlefield, create a Food Token."},
+            {name: "Puny Snack", type_line: "Instant - Adventure", text: "Target creature gets -2/-2 until end of turn."},
         ]
-        "woe" / "ragingfirebolt" => [
-            {name: None, type_line: "Instant", text: "Raging Firebolt deals X damage to target creature, where X is 2 plus the number of instants, sorceries, and cards with adventure in your graveyard."}
+        raging_firebolt: "raging-firebolt" => Normal [
+            {name: "Raging Firebolt", type_line: "Instant", text: "Raging Firebolt deals X damage to target creature, where X is 2 plus the number of instants, sorceries, and cards with adventure in your graveyard."}
         ]
-        "woe" / "picklockprankster" => []
-        "one" / "vraskabetrayalssting" => [
-            {name: None, type_line: "Legendary Planeswalker - Vraska", text: "
+        kianne_dean_of_substance: "kianne-dean-of-substance-imbraham-dean-of-theory" => ModalDfc [
+            {name: "Kianne, Dean of Substance", type_line: "Legendary Creature - Elf Druid", text: "{T}: Exile the top card of your library. If it's a land card, put it into your hand. Otherwise, put a study counter on it."},
+            {name: "Imbraham, Dean of Theory", type_line: "Legendary Creature - Bird Wizard", text: "{X}{U}{U}, {T}: Exile the top X cards of your library and put a study counter on each of them. Then you may put a card you own in exile with a study counter on it into your hand."},
+        ]
+        revival_revenge: "revival-revenge" => Split [
+            {name: "Revival", type_line: "Sorcery", text: "Return target creature card with mana value 3 or less from your graveyard to the battlefield."},
+            {name: "Revenge", type_line: "Sorcery", text: "Double your life total. Target opponent loses half their life, rounded up."},
+        ]
+        vraska_betrayals_sting: "vraska-betrayals-sting" => Normal [
+            {name: "Vraska, Betrayal's Sting", type_line: "Legendary Planeswalker - Vraska", text: "
 Compleated ([B/P] can be paid with B, or 2 life. If life was paid, this planeswalker enters with two fewer loyalty counters.)
 
 [0]: You draw a card and you lose 1 life.
 Proliferate.
 
-[-2]: Target creature becomes a Treasure artifact with \"T: Sacrifice this artifact: Add one mana of any color\" and oses all other card types and abilities.
+[-2]: Target creature becomes a Treasure artifact with \"T: Sacrifice this artifact: Add one mana of any color\" and loses all other card types and abilities.
 
 [-9]: If target player has fewer than nine poison counters, they get a number of poison counters equal to the difference.
 ".trim()}
         ]
     }
-
-    // #[tokio::test]
-    // async fn get_gingerbread_hunter() {
-    //     let text = get_card_text(
-    //         Url::parse("https://mythicspoiler.com/woe/cards/gingerbreadhunter.html").unwrap(),
-    //     )
-    //     .await
-    //     .unwrap();
-
-    //     let [main_card, adventure]: [CardText; 2] = text.try_into().unwrap();
-    //     assert_eq!(main_card.name, None);
-    //     assert_eq!(main_card.type_line.as_deref(), Some(""));
-    //     assert_eq!(
-    //         main_card.text.as_deref(),
-    //         Some("When Gingerbread Hunter enters the battlefield, create a Food Token.")
-    //     );
-
-    //     assert_eq!(adventure.name, None);
-    //     assert_eq!(adventure.type_line.as_deref(), Some("Adventure - Instant"));
-    //     assert_eq!(
-    //         adventure.text.as_deref(),
-    //         Some("Target creature gets -2/-2 until end of turn.")
-    //     );
-    // }
 }
@@ -0,0 +1,628 @@
+use std::{collections::HashSet, sync::Arc};
+
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+use reqwest::Url;
+
+use crate::{
+    cache::{Cache, Known, Seen},
+    canonical,
+    magic_spoiler::MagicSpoiler,
+    mythic::Mythic,
+    transport::Response,
+    Card, Error, Set, Spoiler,
+};
+
+/// A website that publishes spoilers.
+#[async_trait]
+pub trait SpoilerSite: Send + Sync {
+    /// Short identifier used to select this site, e.g. `"mythic"`.
+    fn name(&self) -> &'static str;
+
+    /// Root of the site, every url it produces is relative to this one.
+    fn base_url(&self) -> Url;
+
+    /// Fetches the list of recently spoiled cards, in the order the site lists them.
+    async fn fetch_listing(&self) -> Result<Vec<Spoiler>, Error>;
+
+    /// Lists the sets the site has spoilers for.
+    async fn fetch_sets(&self) -> Result<Vec<Set>, Error> {
+        Err(Error::Unsupported {
+            site: self.name(),
+            operation: "list sets",
+        })
+    }
+
+    /// Fetches every spoiler of the set with the given code, in the order the site lists them.
+    async fn fetch_set(&self, code: &str) -> Result<Vec<Spoiler>, Error> {
+        let _ = code;
+        Err(Error::Unsupported {
+            site: self.name(),
+            operation: "list a set's spoilers",
+        })
+    }
+
+    /// Fills in what the listing couldn't provide, e.g. the card's name.
+    ///
+    /// This is only called for spoilers that made it past the cache, so it is allowed to be
+    /// expensive.
+    async fn resolve(&self, _spoiler: &mut Spoiler) -> Resolution {
+        Resolution::Resolved
+    }
+
+    /// Fetches the text of every face of the card whose page is at `url`.
+    async fn fetch_card_text(&self, url: Url) -> Result<Card, Error>;
+
+    /// [`resolve`](Self::resolve)s `spoiler` and [fetches](Self::fetch_card_text) its text.
+    ///
+    /// Sites that resolve from the card's page override this to only fetch it once. The text is
+    /// `None` if the page couldn't be fetched at all, the resolution says why.
+    async fn resolve_with_text(
+        &self,
+        spoiler: &mut Spoiler,
+    ) -> (Resolution, Option<Result<Card, Error>>) {
+        let resolution = self.resolve(spoiler).await;
+        let card = self.fetch_card_text(spoiler.source_site_url.clone()).await;
+        (resolution, Some(card))
+    }
+}
+
+/// What came of [`SpoilerSite::resolve`].
+#[derive(Debug)]
+pub enum Resolution {
+    /// The spoiler has everything the site can tell about it.
+    Resolved,
+    /// The card's page doesn't exist, or doesn't say what was looked for.
+    NotFound,
+    /// The card's page couldn't be fetched, even after retrying.
+    Failed(Error),
+}
+
+impl Resolution {
+    pub fn is_resolved(&self) -> bool {
+        matches!(self, Self::Resolved)
+    }
+}
+
+/// Fetches the spoilers from `site` that `cache` hasn't seen yet, oldest first, and those it
+/// [knows](Cache::known) got a new image, name or text since.
+///
+/// They're marked as seen right away, see [`candidates`] to only do so once they're delivered.
+#[tracing::instrument(skip_all, fields(site = site.name()))]
+pub async fn new_cards<C: Cache + Send + 'static>(
+    site: &dyn SpoilerSite,
+    mut cache: C,
+) -> Result<Vec<Resolved>, Error> {
+    let candidates = candidates(site, &mut cache).await?;
+    acknowledge(&mut cache, &candidates).await?;
+    Ok(candidates.into_iter().map(|c| c.resolved).collect())
+}
+
+/// Like [`new_cards`], but leaves `cache` untouched, so that the caller can
+/// [`acknowledge`] the spoilers once they're delivered.
+///
+/// Those that aren't are reported again the next time.
+#[tracing::instrument(skip_all, fields(site = site.name()))]
+pub async fn candidates<C: Cache>(
+    site: &dyn SpoilerSite,
+    cache: &mut C,
+) -> Result<Vec<Candidate>, Error> {
+    let spoilers = unseen(site, cache, false).await?;
+    Ok(resolve_all(site, spoilers)
+        .await
+        .into_iter()
+        .filter(|(r, _)| r.seen != Seen::Unchanged)
+        .map(|(resolved, known)| Candidate { resolved, known })
+        .collect())
+}
+
+/// Marks the `delivered` spoilers as seen in `cache`, and commits it.
+pub async fn acknowledge<'c, C: Cache>(
+    cache: &mut C,
+    delivered: impl IntoIterator<Item = &'c Candidate>,
+) -> Result<(), Error> {
+    let known = delivered
+        .into_iter()
+        .map(|c| c.known.clone())
+        .collect::<Vec<_>>();
+    tracing::trace!(count = known.len(), "committing cache");
+    cache.remember(&known).await?;
+    cache.commit().await
+}
+
+/// A spoiler from [`candidates`], that isn't seen until it's [acknowledged](acknowledge).
+#[derive(Debug)]
+pub struct Candidate {
+    pub resolved: Resolved,
+    /// What to remember of the card once it's delivered.
+    known: Known,
+}
+
+impl Candidate {
+    pub(crate) fn into_parts(self) -> (Resolved, Known) {
+        (self.resolved, self.known)
+    }
+}
+
+/// A spoiler with its name resolved, and its card text if it was asked for.
+#[derive(Debug)]
+pub struct Resolved {
+    pub spoiler: Spoiler,
+    /// [`Seen::New`], or [`Seen::Updated`] with what changed.
+    pub seen: Seen,
+    /// Whether resolving the spoiler worked, and why not.
+    pub resolution: Resolution,
+    /// The card's text if it was asked for and its page could be fetched.
+    pub card: Option<Result<Card, Error>>,
+}
+
+/// Like [`new_cards`], but yields each spoiler as soon as it's resolved instead of waiting for
+/// all of them, so they come out in whatever order the pages load.
+///
+/// With `with_text` the card's text is fetched too, see [`SpoilerSite::resolve_with_text`], and
+/// cards whose text shows up later are reported again. The cache is committed once every
+/// spoiler is out, failing to is the stream's last item.
+#[tracing::instrument(skip_all, fields(site = site.name()))]
+pub async fn new_cards_stream<'s, C: Cache + Send + 's>(
+    site: &'s dyn SpoilerSite,
+    mut cache: C,
+    with_text: bool,
+) -> Result<impl Stream<Item = Result<Resolved, Error>> + Send + 's, Error> {
+    let spoilers = unseen(site, &mut cache, with_text).await?;
+    let resolving = futures::stream::iter(spoilers)
+        .map(move |(mut spoiler, previous)| async move {
+            let (resolution, card) = if with_text {
+                site.resolve_with_text(&mut spoiler).await
+            } else {
+                (site.resolve(&mut spoiler).await, None)
+            };
+            (spoiler, previous, resolution, card)
+        })
+        .buffer_unordered(CONCURRENCY);
+    Ok(futures::stream::unfold(
+        (resolving, Some(cache)),
+        |(mut resolving, mut cache)| async move {
+            loop {
+                let c = cache.as_mut()?;
+                let Some((spoiler, previous, resolution, card)) = resolving.next().await else {
+                    tracing::trace!("committing cache");
+                    let committed = c.commit().await;
+                    return committed.err().map(|e| (Err(e), (resolving, None)));
+                };
+                let (seen, known) = compare(previous, &spoiler, card.as_ref());
+                if seen == Seen::Unchanged {
+                    continue;
+                }
+                if let Err(e) = c.remember(&[known]).await {
+                    return Some((Err(e), (resolving, cache)));
+                }
+                let resolved = Resolved {
+                    spoiler,
+                    seen,
+                    resolution,
+                    card,
+                };
+                return Some((Ok(resolved), (resolving, cache)));
+            }
+        },
+    ))
+}
+
+/// Fetches the listing and keeps the spoilers `cache` hasn't seen, and those it knows there's
+/// more to learn about, with what it knows of them, oldest first.
+///
+/// That's cards with a new image, or without a name, or without text if `with_text`. A card
+/// listed twice is only kept once.
+pub(crate) async fn unseen<C: Cache + Send>(
+    site: &dyn SpoilerSite,
+    cache: &mut C,
+    with_text: bool,
+) -> Result<Vec<(Spoiler, Option<Known>)>, Error> {
+    tracing::trace!("fetching listing");
+    let mut listing = site.fetch_listing().await?;
+    let mut keys = HashSet::new();
+    listing.retain(|s| keys.insert(s.key()));
+
+    let new = cache.filter_new(&listing).await?;
+    let new = new.iter().map(Spoiler::key).collect::<HashSet<_>>();
+    let seen = listing
+        .iter()
+        .filter(|s| !new.contains(&s.key()))
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut known = cache.known(&seen).await?.into_iter();
+    let mut spoilers = Vec::new();
+    for spoiler in listing {
+        if new.contains(&spoiler.key()) {
+            spoilers.push((spoiler, None));
+            continue;
+        }
+        let Some(known) = known.next().flatten() else {
+            continue;
+        };
+        let unchanged = known.is_complete(with_text)
+            && canonical(&known.spoiler.image) == canonical(&spoiler.image);
+        if !unchanged {
+            spoilers.push((spoiler, Some(known)));
+        }
+    }
+    tracing::trace!("reversing spoilers list");
+    spoilers.reverse();
+    Ok(spoilers)
+}
+
+/// How many card pages are fetched at once while resolving spoilers.
+pub(crate) const CONCURRENCY: usize = 8;
+
+/// Resolves every spoiler, [`CONCURRENCY`] at a time, keeping their order, along with what's now
+/// known of each.
+pub(crate) async fn resolve_all(
+    site: &dyn SpoilerSite,
+    spoilers: Vec<(Spoiler, Option<Known>)>,
+) -> Vec<(Resolved, Known)> {
+    tracing::trace!(count = spoilers.len(), "resolving spoilers");
+    let now = std::time::Instant::now();
+    let resolved = futures::stream::iter(spoilers.into_iter().map(
+        |(mut spoiler, previous)| async move {
+            let resolution = site.resolve(&mut spoiler).await;
+            if let Resolution::Failed(e) = &resolution {
+                tracing::warn!(url = %spoiler.source_site_url, ?e, "failed to resolve");
+            }
+            let (seen, known) = compare(previous, &spoiler, None);
+            let resolved = Resolved {
+                spoiler,
+                seen,
+                resolution,
+                card: None,
+            };
+            (resolved, known)
+        },
+    ))
+    .buffered(CONCURRENCY)
+    .collect()
+    .await;
+    tracing::trace!(elapsed = ?now.elapsed(), "done resolving spoilers");
+    resolved
+}
+
+/// How what resolving `spoiler` taught compares to what was known, and everything now known.
+fn compare(
+    previous: Option<Known>,
+    spoiler: &Spoiler,
+    card: Option<&Result<Card, Error>>,
+) -> (Seen, Known) {
+    let now = Known {
+        spoiler: spoiler.clone(),
+        card: card.and_then(|c| c.as_ref().ok()).cloned(),
+    };
+    match previous {
+        None => (Seen::New, now),
+        Some(previous) => (
+            previous
+                .changes(&now)
+                .map_or(Seen::Unchanged, Seen::Updated),
+            now.or(previous),
+        ),
+    }
+}
+
+/// Makes sure a listing page still looks like one.
+///
+/// `found` is how many elements matched `selector`, the spoilers were parsed out of them. A page
+/// with content but no matches means the markup changed, matches none of which could be parsed
+/// mean their insides did.
+pub(crate) fn check_listing(
+    response: &Response,
+    selector: &str,
+    found: usize,
+    spoilers: Vec<Spoiler>,
+) -> Result<Vec<Spoiler>, Error> {
+    if found == 0 && !response.body.trim().is_empty() {
+        return Err(Error::Layout {
+            url: response.url.clone(),
+            context: format!("no {selector} in a {} byte page", response.body.len()),
+        });
+    }
+    if found > 0 && spoilers.is_empty() {
+        return Err(Error::Parse {
+            url: response.url.clone(),
+            context: format!("none of the {found} {selector} could be parsed"),
+        });
+    }
+    if spoilers.len() < found {
+        tracing::warn!(url = %response.url, found, parsed = spoilers.len(), "skipped some cards");
+    }
+    Ok(spoilers)
+}
+
+#[allow(dead_code)]
+fn _assert() {
+    fn is_send<T: Send>(_: T) {}
+    is_send(new_cards(&Mythic::default(), super::cache::empty::Empty));
+    is_send(candidates(
+        &Mythic::default(),
+        &mut super::cache::empty::Empty,
+    ));
+    is_send(new_cards_stream(
+        &Mythic::default(),
+        super::cache::empty::Empty,
+        true,
+    ));
+}
+
+/// The set of known spoiler sites, looked up by [`SpoilerSite::name`].
+#[derive(Clone)]
+pub struct Registry {
+    sites: Vec<Arc<dyn SpoilerSite>>,
+}
+
+impl Registry {
+    /// A registry with no sites in it.
+    pub fn empty() -> Self {
+        Self { sites: Vec::new() }
+    }
+
+    /// Adds a site to the registry, replacing any site with the same name.
+    pub fn register<S: SpoilerSite + 'static>(&mut self, site: S) -> &mut Self {
+        self.register_arc(Arc::new(site))
+    }
+
+    pub fn register_arc(&mut self, site: Arc<dyn SpoilerSite>) -> &mut Self {
+        match self.sites.iter_mut().find(|s| s.name() == site.name()) {
+            Some(existing) => *existing = site,
+            None => self.sites.push(site),
+        }
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn SpoilerSite>> {
+        self.sites.iter().find(|s| s.name() == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<dyn SpoilerSite>> {
+        self.sites.iter()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.sites.iter().map(|s| s.name())
+    }
+}
+
+impl Default for Registry {
+    /// Every site supported by this crate, [`Mythic`] first.
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry
+            .register(Mythic::default())
+            .register(MagicSpoiler::default());
+        registry
+    }
+}
+
+impl std::fmt::Debug for Registry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        cache::{empty::Empty, Changes},
+        fixtures, transport,
+    };
+
+    struct Fake(&'static str);
+
+    #[async_trait]
+    impl SpoilerSite for Fake {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn base_url(&self) -> Url {
+            Url::parse("https://example.com/").unwrap()
+        }
+
+        async fn fetch_listing(&self) -> Result<Vec<Spoiler>, Error> {
+            Ok(vec![])
+        }
+
+        async fn fetch_card_text(&self, _: Url) -> Result<Card, Error> {
+            Ok(Card::default())
+        }
+    }
+
+    #[tokio::test]
+    async fn sets_are_unsupported_by_default() {
+        assert!(matches!(
+            Fake("scryfall").fetch_sets().await,
+            Err(Error::Unsupported {
+                site: "scryfall",
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn stream_yields_resolved_spoilers() {
+        let url = |s: &str| Url::parse(&format!("https://mythicspoiler.com/{s}")).unwrap();
+        let page = |s: &'static str| async move {
+            fixtures::handwritten(&format!("mythic/woe/{s}.html")).await
+        };
+        let transport = transport::Fake::new()
+            .with_page(
+                url("newspoilers.html"),
+                r#"
+                <div class="grid-card"><a href="woe/cards/ragingfirebolt.html"><img src="woe/cards/ragingfirebolt.jpg"></a></div>
+                <div class="grid-card"><a href="woe/cards/gingerbreadhunter.html"><img src="woe/cards/gingerbreadhunter.png"></a></div>
+                "#,
+            )
+            .with_page(url("woe/cards/ragingfirebolt.html"), page("ragingfirebolt").await)
+            .with_page(
+                url("woe/cards/gingerbreadhunter.html"),
+                page("gingerbreadhunter").await,
+            );
+        let mythic = Mythic::new(transport.clone());
+
+        let mut resolved = new_cards_stream(&mythic, Empty, true)
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        resolved.sort_by(|a, b| a.spoiler.name.cmp(&b.spoiler.name));
+        let [hunter, firebolt]: [Resolved; 2] = resolved.try_into().unwrap();
+        assert_eq!(hunter.spoiler.name.as_deref(), Some("Gingerbread Hunter"));
+        assert_eq!(hunter.card.unwrap().unwrap().faces.len(), 2);
+        assert_eq!(firebolt.spoiler.name.as_deref(), Some("Raging Firebolt"));
+        assert_eq!(firebolt.card.unwrap().unwrap().faces.len(), 1);
+        // the name and the text come from the same fetch of the page
+        assert_eq!(transport.requests(&url("woe/cards/ragingfirebolt.html")), 1);
+
+        let without_text = new_cards_stream(&mythic, Empty, false)
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(without_text.len(), 2);
+        assert!(without_text.iter().all(|r| r.card.is_none()));
+    }
+
+    #[tokio::test]
+    async fn cards_filled_in_later_are_updates() {
+        let url = |s: &str| Url::parse(&format!("https://mythicspoiler.com/{s}")).unwrap();
+        let page = |s: &'static str| async move {
+            fixtures::handwritten(&format!("mythic/woe/{s}.html")).await
+        };
+        let listing = |hunter: &str| {
+            format!(
+                r#"
+                <div class="grid-card"><a href="woe/cards/picklockprankster.html"><img src="woe/cards/picklockprankster.jpg"></a></div>
+                <div class="grid-card"><a href="woe/cards/gingerbreadhunter.html"><img src="woe/cards/{hunter}"></a></div>
+                "#
+            )
+        };
+        let mythic = |hunter: &str, prankster: String| {
+            Mythic::new(
+                transport::Fake::new()
+                    .with_page(url("newspoilers.html"), listing(hunter))
+                    .with_page(url("woe/cards/picklockprankster.html"), prankster),
+            )
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache");
+        let run = |mythic: Mythic| {
+            let path = path.clone();
+            async move {
+                let cache = crate::cache::file::File::new(path)
+                    .await
+                    .unwrap()
+                    .updates(true);
+                new_cards_stream(&mythic, cache, true)
+                    .await
+                    .unwrap()
+                    .map(|r| {
+                        let r = r.unwrap();
+                        (r.spoiler.key(), r.seen)
+                    })
+                    .collect::<std::collections::HashMap<_, _>>()
+                    .await
+            }
+        };
+
+        // the prankster's page wasn't filled in yet
+        let first = run(mythic(
+            "gingerbreadhunter.jpg",
+            page("picklockprankster").await,
+        ))
+        .await;
+        assert_eq!(first["woe/picklockprankster"], Seen::New);
+        assert_eq!(first["woe/gingerbreadhunter"], Seen::New);
+
+        // its page was, under the firebolt's name, and the hunter got a better image
+        let filled = page("ragingfirebolt").await;
+        let second = run(mythic("gingerbreadhunter.png", filled.clone())).await;
+        assert_eq!(
+            second["woe/picklockprankster"],
+            Seen::Updated(Changes {
+                previous_image: None,
+                name: true,
+                text: true,
+            })
+        );
+        assert_eq!(
+            second["woe/gingerbreadhunter"],
+            Seen::Updated(Changes {
+                previous_image: Some(url("woe/cards/gingerbreadhunter.jpg")),
+                name: false,
+                text: false,
+            })
+        );
+
+        assert!(run(mythic("gingerbreadhunter.png", filled))
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_acknowledged_candidates_are_seen() {
+        let mythic = Mythic::new(transport::Fake::new().with_page(
+            Url::parse("https://mythicspoiler.com/newspoilers.html").unwrap(),
+            r#"
+            <div class="grid-card"><a href="woe/cards/ragingfirebolt.html"><img src="woe/cards/ragingfirebolt.jpg"></a></div>
+            <div class="grid-card"><a href="woe/cards/gingerbreadhunter.html"><img src="woe/cards/gingerbreadhunter.jpg"></a></div>
+            "#,
+        ));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache");
+        let keys = |candidates: &[Candidate]| {
+            candidates
+                .iter()
+                .map(|c| c.resolved.spoiler.key())
+                .collect::<Vec<_>>()
+        };
+
+        let mut cache = crate::cache::file::File::new(&path).await.unwrap();
+        let first = candidates(&mythic, &mut cache).await.unwrap();
+        assert_eq!(
+            keys(&first),
+            ["woe/gingerbreadhunter", "woe/ragingfirebolt"]
+        );
+        // posting the firebolt failed
+        acknowledge(&mut cache, &first[..1]).await.unwrap();
+        drop(cache);
+
+        let mut cache = crate::cache::file::File::new(&path).await.unwrap();
+        let second = candidates(&mythic, &mut cache).await.unwrap();
+        assert_eq!(keys(&second), ["woe/ragingfirebolt"]);
+    }
+
+    #[test]
+    fn default_registry_has_every_site() {
+        let registry = Registry::default();
+        assert_eq!(
+            registry.names().collect::<Vec<_>>(),
+            ["mythic", "magic-spoiler"]
+        );
+        assert!(registry.get("mythic").is_some());
+        assert!(registry.get("magic-spoiler").is_some());
+        assert!(registry.get("scryfall").is_none());
+    }
+
+    #[test]
+    fn register_replaces_sites_with_the_same_name() {
+        let mut registry = Registry::default();
+        registry.register(Fake("scryfall")).register(Fake("mythic"));
+        assert_eq!(
+            registry.names().collect::<Vec<_>>(),
+            ["mythic", "magic-spoiler", "scryfall"]
+        );
+        assert_eq!(
+            registry.get("mythic").unwrap().base_url().as_str(),
+            "https://example.com/"
+        );
+    }
+}
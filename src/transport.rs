@@ -0,0 +1,313 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use reqwest::{header::HeaderMap, StatusCode, Url};
+
+use crate::Error;
+
+pub mod polite;
+
+pub use polite::Polite;
+
+/// How the scrapers talk to the outside world.
+///
+/// [`reqwest::Client`] implements this, so user agent, proxies, timeouts and TLS options are
+/// configured by building the client with [`reqwest::ClientBuilder`] and handing it to the site,
+/// e.g. [`Mythic::new`](crate::mythic::Mythic::new). Tests can use [`Fake`] instead.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    async fn get(&self, url: Url) -> Result<Response, Error>;
+
+    /// Whether this already waits out and retries `429`s and `503`s, like [`Polite`] does, so
+    /// that [`Backoff`] doesn't retry them again.
+    fn handles_rate_limits(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub url: Url,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+impl Response {
+    /// Turns anything but a `2xx` into an [`Error::Status`].
+    pub fn error_for_status(self) -> Result<Self, Error> {
+        if self.status.is_success() {
+            Ok(self)
+        } else {
+            Err(Error::Status {
+                url: self.url,
+                status: self.status,
+            })
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for reqwest::Client {
+    async fn get(&self, url: Url) -> Result<Response, Error> {
+        let response = reqwest::Client::get(self, url).send().await?;
+        Ok(Response {
+            url: response.url().clone(),
+            status: response.status(),
+            headers: response.headers().clone(),
+            body: response.text().await?,
+        })
+    }
+}
+
+#[async_trait]
+impl<T: Transport + ?Sized> Transport for Arc<T> {
+    async fn get(&self, url: Url) -> Result<Response, Error> {
+        (**self).get(url).await
+    }
+
+    fn handles_rate_limits(&self) -> bool {
+        (**self).handles_rate_limits()
+    }
+}
+
+/// The transport used by sites that weren't given one, a [`reqwest::Client`] with the default
+/// settings wrapped in [`Polite`], shared by the whole process.
+pub fn shared() -> Arc<dyn Transport> {
+    static CLIENT: OnceLock<Arc<dyn Transport>> = OnceLock::new();
+    CLIENT
+        .get_or_init(|| Arc::new(Polite::new(reqwest::Client::default())))
+        .clone()
+}
+
+/// How often, and how patiently, to retry a request that failed for a reason that might go away
+/// on its own: a timeout, a connection error or a `5xx`, `408` or `429` status.
+///
+/// A `429` or `503` is waited out for as long as its `Retry-After` asks, and not retried if
+/// that's longer than [`max`](Self::max), or if the transport
+/// [handles rate limits](Transport::handles_rate_limits) itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub retries: u32,
+    /// The wait before the first retry, doubled before every following one.
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    /// 3 retries, waiting half a second, then one and then two.
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Backoff {
+    /// Never retries.
+    pub fn none() -> Self {
+        Self {
+            retries: 0,
+            ..Self::default()
+        }
+    }
+
+    /// Gets `url`, retrying transient failures.
+    ///
+    /// Once out of retries the last response is returned as is, whatever its status.
+    pub async fn get(&self, transport: &dyn Transport, url: Url) -> Result<Response, Error> {
+        let mut delay = self.initial;
+        let mut attempt = 0;
+        loop {
+            let result = transport.get(url.clone()).await;
+            let wait = match &result {
+                Ok(response)
+                    if matches!(
+                        response.status,
+                        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+                    ) =>
+                {
+                    if transport.handles_rate_limits() {
+                        None
+                    } else {
+                        match polite::retry_after(response) {
+                            Some(retry_after) => (retry_after <= self.max).then_some(retry_after),
+                            None => Some(delay),
+                        }
+                    }
+                }
+                Ok(response) => (response.status.is_server_error()
+                    || response.status == StatusCode::REQUEST_TIMEOUT)
+                    .then_some(delay),
+                Err(Error::Reqwest(e)) => {
+                    (e.is_timeout() || e.is_connect() || e.is_request()).then_some(delay)
+                }
+                Err(Error::Io(_)) => Some(delay),
+                Err(_) => None,
+            };
+            let Some(wait) = wait.filter(|_| attempt < self.retries) else {
+                return result;
+            };
+            attempt += 1;
+            tracing::debug!(%url, attempt, ?wait, "retrying");
+            tokio::time::sleep(wait).await;
+            delay = (delay * 2).min(self.max);
+        }
+    }
+}
+
+/// An in-memory transport that serves canned pages, anything else is a `404`.
+#[derive(Debug, Default, Clone)]
+pub struct Fake {
+    pages: HashMap<Url, (StatusCode, HeaderMap, String)>,
+    requests: Arc<Mutex<HashMap<Url, usize>>>,
+}
+
+impl Fake {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves `body` with a `200` at `url`.
+    pub fn with_page<B: Into<String>>(self, url: Url, body: B) -> Self {
+        self.with_response(url, StatusCode::OK, body)
+    }
+
+    pub fn with_response<B: Into<String>>(self, url: Url, status: StatusCode, body: B) -> Self {
+        self.with_headers(url, status, HeaderMap::new(), body)
+    }
+
+    pub fn with_headers<B: Into<String>>(
+        mut self,
+        url: Url,
+        status: StatusCode,
+        headers: HeaderMap,
+        body: B,
+    ) -> Self {
+        self.pages.insert(url, (status, headers, body.into()));
+        self
+    }
+
+    /// How many times `url` was requested, by this and its clones.
+    pub fn requests(&self, url: &Url) -> usize {
+        self.requests
+            .lock()
+            .unwrap()
+            .get(url)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl Transport for Fake {
+    async fn get(&self, url: Url) -> Result<Response, Error> {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry(url.clone())
+            .or_default() += 1;
+        let (status, headers, body) = self
+            .pages
+            .get(&url)
+            .cloned()
+            .unwrap_or_else(|| (StatusCode::NOT_FOUND, HeaderMap::new(), String::new()));
+        Ok(Response {
+            url,
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use reqwest::header::{HeaderValue, RETRY_AFTER};
+    use tokio::time::Instant;
+
+    use super::*;
+
+    /// Answers with each status in turn, then `200`s.
+    struct Scripted(Mutex<Vec<StatusCode>>);
+
+    #[async_trait]
+    impl Transport for Scripted {
+        async fn get(&self, url: Url) -> Result<Response, Error> {
+            let mut statuses = self.0.lock().unwrap();
+            let status = if statuses.is_empty() {
+                StatusCode::OK
+            } else {
+                statuses.remove(0)
+            };
+            Ok(Response {
+                url,
+                status,
+                headers: HeaderMap::new(),
+                body: String::new(),
+            })
+        }
+    }
+
+    fn scripted(statuses: &[StatusCode]) -> Scripted {
+        Scripted(Mutex::new(statuses.to_vec()))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff_retries_transient_failures() {
+        let url = Url::parse("https://example.com/").unwrap();
+        let transport = scripted(&[StatusCode::SERVICE_UNAVAILABLE, StatusCode::GATEWAY_TIMEOUT]);
+        let start = Instant::now();
+        let response = Backoff::default().get(&transport, url.clone()).await;
+        assert_eq!(response.unwrap().status, StatusCode::OK);
+        assert_eq!(start.elapsed(), Duration::from_millis(500 + 1000));
+
+        let transport = scripted(&[StatusCode::INTERNAL_SERVER_ERROR; 5]);
+        let response = Backoff::default().get(&transport, url.clone()).await;
+        assert_eq!(response.unwrap().status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(transport.0.lock().unwrap().len(), 1);
+
+        // not worth retrying
+        let transport = scripted(&[StatusCode::NOT_FOUND]);
+        let response = Backoff::default().get(&transport, url).await;
+        assert_eq!(response.unwrap().status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff_leaves_rate_limits_to_polite() {
+        let url = Url::parse("https://example.com/").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        let fake =
+            Fake::new().with_headers(url.clone(), StatusCode::TOO_MANY_REQUESTS, headers, "");
+
+        // longer than it's willing to wait
+        let response = Backoff::default().get(&fake, url.clone()).await;
+        assert_eq!(response.unwrap().status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(fake.requests(&url), 1);
+
+        // polite waits it out twice, and that's it
+        let polite = Polite::new(fake.clone()).respect_robots(false);
+        let start = Instant::now();
+        let response = Backoff::default().get(&polite, url.clone()).await;
+        assert_eq!(response.unwrap().status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(fake.requests(&url), 1 + 3);
+        assert_eq!(start.elapsed(), Duration::from_secs(2 * 120));
+
+        let patient = Backoff {
+            max: Duration::from_secs(5 * 60),
+            ..Backoff::default()
+        };
+        let start = Instant::now();
+        patient.get(&fake, url.clone()).await.unwrap();
+        assert_eq!(fake.requests(&url), 4 + 4);
+        assert_eq!(start.elapsed(), Duration::from_secs(3 * 120));
+    }
+}
@@ -0,0 +1,532 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use reqwest::{header::RETRY_AFTER, StatusCode, Url};
+use tokio::{
+    sync::{OnceCell, Semaphore},
+    time::Instant,
+};
+
+use super::{Response, Transport};
+use crate::Error;
+
+/// Wraps a transport so that it doesn't get us banned.
+///
+/// - at most [`concurrency`](Self::concurrency) requests are in flight at once,
+/// - each host gets its own token bucket, refilled at [`rate`](Self::rate) requests per second,
+/// - a `429 Too Many Requests` or `503 Service Unavailable` pauses every request to that host for
+///   as long as its `Retry-After` header asks, and the request is then retried,
+/// - urls the host's `robots.txt` disallows fail with [`Error::DisallowedByRobots`].
+pub struct Polite<T> {
+    inner: T,
+    permits: Semaphore,
+    per_second: f64,
+    burst: f64,
+    max_retries: u32,
+    max_retry_after: Duration,
+    user_agent: String,
+    respect_robots: bool,
+    hosts: Mutex<HashMap<String, Bucket>>,
+    /// Each origin's `robots.txt`, fetched once by whoever needs it first.
+    robots: Mutex<HashMap<String, Arc<OnceCell<Arc<Robots>>>>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    paused_until: Option<Instant>,
+}
+
+impl<T: Transport> Polite<T> {
+    /// Wraps `inner` with 8 concurrent requests, 2 requests per second per host with bursts of
+    /// 4, up to 2 retries after a `429` and `robots.txt` checks for the `mtg-spoilers` user agent.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            permits: Semaphore::new(8),
+            per_second: 2.,
+            burst: 4.,
+            max_retries: 2,
+            max_retry_after: Duration::from_secs(5 * 60),
+            user_agent: "mtg-spoilers".into(),
+            respect_robots: true,
+            hosts: Default::default(),
+            robots: Default::default(),
+        }
+    }
+
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.permits = Semaphore::new(concurrency.max(1));
+        self
+    }
+
+    /// Requests per second allowed to each host, and how many can be made at once after a
+    /// quiet period.
+    pub fn rate(mut self, per_second: f64, burst: u32) -> Self {
+        self.per_second = per_second;
+        self.burst = f64::from(burst.max(1));
+        self
+    }
+
+    /// How many times a request is retried after being told to slow down, and the longest
+    /// `Retry-After` that is honored, anything longer fails the request instead. The host is
+    /// paused for as long as it asked either way.
+    pub fn retries(mut self, max_retries: u32, max_retry_after: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.max_retry_after = max_retry_after;
+        self
+    }
+
+    /// The user agent `robots.txt` rules are matched against, by its product token like
+    /// `mtg-spoilers` in `mtg-spoilers/0.1`. This doesn't change the `User-Agent` header, that is
+    /// configured on the wrapped transport.
+    pub fn user_agent<S: Into<String>>(mut self, user_agent: S) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    pub fn respect_robots(mut self, respect_robots: bool) -> Self {
+        self.respect_robots = respect_robots;
+        self
+    }
+
+    /// Waits until `host` has a token to spend.
+    async fn throttle(&self, host: &str) {
+        loop {
+            let wait = {
+                let mut hosts = self.hosts.lock().unwrap();
+                let now = Instant::now();
+                let bucket = hosts.entry(host.to_owned()).or_insert(Bucket {
+                    tokens: self.burst,
+                    refilled_at: now,
+                    paused_until: None,
+                });
+                match bucket.paused_until {
+                    Some(until) if until > now => until - now,
+                    _ => {
+                        let elapsed = (now - bucket.refilled_at).as_secs_f64();
+                        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+                        bucket.refilled_at = now;
+                        if bucket.tokens >= 1. {
+                            bucket.tokens -= 1.;
+                            return;
+                        }
+                        Duration::from_secs_f64((1. - bucket.tokens) / self.per_second)
+                    }
+                }
+            };
+            tracing::trace!(host, ?wait, "throttling");
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn pause(&self, host: &str, duration: Duration) {
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(bucket) = hosts.get_mut(host) {
+            let until = Instant::now() + duration;
+            bucket.paused_until = Some(bucket.paused_until.map_or(until, |u| u.max(until)));
+        }
+    }
+
+    async fn fetch(&self, url: Url) -> Result<Response, Error> {
+        let host = url.host_str().unwrap_or_default().to_owned();
+        let mut retries = 0;
+        loop {
+            self.throttle(&host).await;
+            let response = {
+                let _permit = self.permits.acquire().await.unwrap();
+                self.inner.get(url.clone()).await?
+            };
+            if !matches!(
+                response.status,
+                StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+            ) {
+                return Ok(response);
+            }
+            let retry_after = retry_after(&response).unwrap_or(Duration::from_secs(1 << retries));
+            tracing::debug!(%url, ?retry_after, status = %response.status, "asked to slow down");
+            // even if this one gives up, the others shouldn't hit the host in the meantime
+            self.pause(&host, retry_after);
+            if retries >= self.max_retries || retry_after > self.max_retry_after {
+                return Ok(response);
+            }
+            retries += 1;
+        }
+    }
+
+    async fn robots(&self, url: &Url) -> Result<Arc<Robots>, Error> {
+        let origin = url.origin().ascii_serialization();
+        let cell = self
+            .robots
+            .lock()
+            .unwrap()
+            .entry(origin)
+            .or_default()
+            .clone();
+        let robots = cell
+            .get_or_try_init(|| async {
+                let response = self.fetch(url.join("/robots.txt")?).await?;
+                // a missing or broken robots.txt doesn't forbid anything
+                let robots = if response.status.is_success() {
+                    Robots::parse(&response.body, &self.user_agent)
+                } else {
+                    Robots::default()
+                };
+                Ok::<_, Error>(Arc::new(robots))
+            })
+            .await?;
+        Ok(robots.clone())
+    }
+}
+
+/// How long the `Retry-After` header asks to wait, given in seconds or as a date.
+pub(crate) fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    // a date that already passed means right away
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+#[async_trait]
+impl<T: Transport> Transport for Polite<T> {
+    async fn get(&self, url: Url) -> Result<Response, Error> {
+        if self.respect_robots && !self.robots(&url).await?.allows(&url) {
+            return Err(Error::DisallowedByRobots(url));
+        }
+        self.fetch(url).await
+    }
+
+    fn handles_rate_limits(&self) -> bool {
+        true
+    }
+}
+
+/// The rules of a `robots.txt` that apply to one user agent.
+#[derive(Debug, Default)]
+struct Robots {
+    /// `(allow, pattern)`
+    rules: Vec<(bool, String)>,
+}
+
+impl Robots {
+    /// Keeps the rules of the groups naming `user_agent`'s product token, the part before any
+    /// `/`, or of the `*` group if none does.
+    fn parse(robots: &str, user_agent: &str) -> Self {
+        let product = user_agent
+            .split(|c: char| c == '/' || c.is_whitespace())
+            .next()
+            .unwrap_or_default();
+        let mut specific = Vec::new();
+        let mut wildcard = Vec::new();
+        let (mut for_us, mut for_all, mut in_agents) = (false, false, false);
+        for line in robots.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match &key.trim().to_lowercase()[..] {
+                "user-agent" => {
+                    if !in_agents {
+                        (for_us, for_all) = (false, false);
+                    }
+                    in_agents = true;
+                    for_all |= value == "*";
+                    for_us |= value.eq_ignore_ascii_case(product);
+                }
+                rule @ ("allow" | "disallow") => {
+                    in_agents = false;
+                    // an empty disallow allows everything
+                    if value.is_empty() {
+                        continue;
+                    }
+                    let rule = (rule == "allow", value.to_owned());
+                    if for_us {
+                        specific.push(rule.clone());
+                    }
+                    if for_all {
+                        wildcard.push(rule);
+                    }
+                }
+                _ => in_agents = false,
+            }
+        }
+        Self {
+            rules: if specific.is_empty() {
+                wildcard
+            } else {
+                specific
+            },
+        }
+    }
+
+    /// The longest matching rule wins, allow wins ties.
+    fn allows(&self, url: &Url) -> bool {
+        let path = match url.query() {
+            Some(query) => format!("{}?{query}", url.path()),
+            None => url.path().to_owned(),
+        };
+        self.rules
+            .iter()
+            .filter(|(_, pattern)| matches(pattern, &path))
+            .max_by_key(|(allow, pattern)| (pattern.len(), *allow))
+            .is_none_or(|(allow, _)| *allow)
+    }
+}
+
+/// Matches a `robots.txt` path pattern, where `*` is any sequence and a trailing `$` anchors
+/// the end.
+fn matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(p) => (p, true),
+        None => (pattern, false),
+    };
+    let mut parts = pattern.split('*');
+    let Some(rest) = path.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+    let parts = parts.collect::<Vec<_>>();
+    let mut rest = rest;
+    for (i, part) in parts.iter().enumerate() {
+        if anchored && i == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    !anchored || rest.is_empty()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use reqwest::header::{HeaderMap, HeaderValue};
+
+    use super::*;
+    use crate::transport::Fake;
+
+    /// Counts the requests it gets and how many were in flight at once.
+    #[derive(Default)]
+    struct Counting {
+        requests: AtomicUsize,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+        /// how many requests get a `429` before they start succeeding
+        too_many: usize,
+    }
+
+    #[async_trait]
+    impl Transport for Counting {
+        async fn get(&self, url: Url) -> Result<Response, Error> {
+            let n = self.requests.fetch_add(1, Ordering::SeqCst);
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            let (status, mut headers) = (StatusCode::OK, HeaderMap::new());
+            let status = if n < self.too_many {
+                headers.insert(RETRY_AFTER, HeaderValue::from_static("30"));
+                StatusCode::TOO_MANY_REQUESTS
+            } else {
+                status
+            };
+            Ok(Response {
+                url,
+                status,
+                headers,
+                body: String::new(),
+            })
+        }
+    }
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn caps_concurrency() {
+        let polite = Polite::new(Counting::default())
+            .concurrency(2)
+            .rate(1000., 1000)
+            .respect_robots(false);
+        futures::future::join_all(
+            (0..10).map(|i| polite.get(url(&format!("https://example.com/{i}")))),
+        )
+        .await;
+        assert_eq!(polite.inner.requests.load(Ordering::SeqCst), 10);
+        assert_eq!(polite.inner.max_in_flight.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limits_each_host() {
+        let polite = Polite::new(Counting::default())
+            .rate(1., 1)
+            .respect_robots(false);
+        let start = Instant::now();
+        futures::future::join_all([
+            polite.get(url("https://a.example.com/1")),
+            polite.get(url("https://a.example.com/2")),
+            polite.get(url("https://a.example.com/3")),
+            polite.get(url("https://b.example.com/1")),
+        ])
+        .await;
+        // a's second and third requests wait a second each, b doesn't wait for a
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(2), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(3), "{elapsed:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn honors_retry_after() {
+        let polite = Polite::new(Counting {
+            too_many: 1,
+            ..Default::default()
+        })
+        .respect_robots(false);
+        let start = Instant::now();
+        let response = polite.get(url("https://example.com/")).await.unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(polite.inner.requests.load(Ordering::SeqCst), 2);
+        assert!(start.elapsed() >= Duration::from_secs(30));
+
+        let impatient = Polite::new(Counting {
+            too_many: 1,
+            ..Default::default()
+        })
+        .retries(2, Duration::from_secs(10))
+        .respect_robots(false);
+        let start = Instant::now();
+        let response = impatient.get(url("https://example.com/")).await.unwrap();
+        assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // but the host still gets the 30 seconds it asked for
+        let response = impatient.get(url("https://example.com/")).await.unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+        assert!(start.elapsed() >= Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn respects_robots_txt() {
+        let robots = "
+            User-agent: *
+            Disallow: /private
+            Allow: /private/ok$
+
+            User-agent: GoogleBot
+            Disallow: /
+        ";
+        let polite = Polite::new(
+            Fake::new()
+                .with_page(url("https://example.com/robots.txt"), robots)
+                .with_page(url("https://example.com/public"), "public"),
+        );
+        assert_eq!(
+            polite
+                .get(url("https://example.com/public"))
+                .await
+                .unwrap()
+                .body,
+            "public"
+        );
+        assert!(matches!(
+            polite.get(url("https://example.com/private/x")).await,
+            Err(Error::DisallowedByRobots(_))
+        ));
+        assert!(polite
+            .get(url("https://example.com/private/ok"))
+            .await
+            .is_ok());
+
+        // no robots.txt, no rules
+        let polite = Polite::new(Fake::new());
+        assert!(polite.get(url("https://example.com/private")).await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_robots_txt_only_holds_up_its_host() {
+        struct Slow;
+
+        #[async_trait]
+        impl Transport for Slow {
+            async fn get(&self, url: Url) -> Result<Response, Error> {
+                if url.host_str() == Some("slow.example.com") {
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                }
+                Fake::new().get(url).await
+            }
+        }
+
+        let polite = Polite::new(Slow);
+        let start = Instant::now();
+        let (slow, fast) = tokio::join!(polite.get(url("https://slow.example.com/")), async {
+            polite.get(url("https://fast.example.com/")).await?;
+            Ok::<_, Error>(start.elapsed())
+        });
+        slow.unwrap();
+        assert!(fast.unwrap() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn retry_after_in_seconds_or_as_a_date() {
+        let response = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RETRY_AFTER, value.parse().unwrap());
+            Response {
+                url: url("https://example.com/"),
+                status: StatusCode::TOO_MANY_REQUESTS,
+                headers,
+                body: String::new(),
+            }
+        };
+        assert_eq!(
+            retry_after(&response("120")),
+            Some(Duration::from_secs(120))
+        );
+        let in_a_minute = SystemTime::now() + Duration::from_secs(60);
+        let wait = retry_after(&response(&httpdate::fmt_http_date(in_a_minute))).unwrap();
+        assert!(
+            wait > Duration::from_secs(55) && wait <= Duration::from_secs(60),
+            "{wait:?}"
+        );
+        assert_eq!(
+            retry_after(&response("Wed, 21 Oct 2015 07:28:00 GMT")),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after(&response("soon")), None);
+    }
+
+    #[test]
+    fn robots_groups_match_the_product_token() {
+        let robots = "
+            User-agent: s
+            User-agent: mtg
+            Disallow: /substrings
+
+            User-agent: MTG-Spoilers
+            Disallow: /ours
+        ";
+        let robots = Robots::parse(robots, "mtg-spoilers/0.1 (+https://example.com)");
+        assert!(robots.allows(&url("https://example.com/substrings")));
+        assert!(!robots.allows(&url("https://example.com/ours")));
+    }
+
+    #[test]
+    fn robots_patterns() {
+        assert!(matches("/", "/anything"));
+        assert!(!matches("/cards/*.html", "/woe/cards/x.html"));
+        assert!(matches("/*/cards/*.html", "/woe/cards/x.html"));
+        assert!(matches("/*.jpg$", "/woe/cards/x.jpg"));
+        assert!(!matches("/*.jpg$", "/woe/cards/x.jpg?size=2"));
+        assert!(!matches("/private", "/public"));
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{
+    channel::{mpsc, oneshot},
+    SinkExt, Stream,
+};
+use tokio::task::JoinHandle;
+
+use crate::{
+    cache::Cache,
+    site::{self, Registry, Resolved, SpoilerSite},
+    Error,
+};
+
+/// Polls spoiler sites forever, reporting each new spoiler once.
+///
+/// ```no_run
+/// # async fn f() -> Result<(), mtg_spoilers::Error> {
+/// use futures::StreamExt;
+/// use mtg_spoilers::{cache::empty::Empty, site::Registry, watch::Watcher};
+///
+/// let (mut spoilers, shutdown) = Watcher::new(Empty)
+///     .registry(&Registry::default())
+///     .spawn();
+/// // the stream ends once the watcher is shut down
+/// let stop = tokio::spawn(async move {
+///     tokio::time::sleep(std::time::Duration::from_secs(60 * 60)).await;
+///     shutdown.shutdown().await
+/// });
+/// while let Some(spoiler) = spoilers.next().await {
+///     println!("{spoiler:?}");
+/// }
+/// stop.await.unwrap()
+/// # }
+/// ```
+pub struct Watcher<C> {
+    cache: C,
+    sites: Vec<Arc<dyn SpoilerSite>>,
+    interval: Duration,
+    jitter: Duration,
+    buffer: usize,
+}
+
+impl<C: Cache + Send + 'static> Watcher<C> {
+    pub fn new(cache: C) -> Self {
+        Self {
+            cache,
+            sites: Vec::new(),
+            interval: Duration::from_secs(5 * 60),
+            jitter: Duration::from_secs(30),
+            buffer: 64,
+        }
+    }
+
+    pub fn site(mut self, site: Arc<dyn SpoilerSite>) -> Self {
+        self.sites.push(site);
+        self
+    }
+
+    /// Watches every site in the registry.
+    pub fn registry(mut self, registry: &Registry) -> Self {
+        self.sites.extend(registry.iter().cloned());
+        self
+    }
+
+    /// Time between the end of a poll and the start of the next one. Defaults to 5 minutes.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Up to how much time is randomly added to each interval, so that several watchers don't
+    /// hit the sites in lockstep. Defaults to 30 seconds.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// How many spoilers can be waiting to be consumed before polling pauses. Defaults to 64.
+    pub fn buffer(mut self, buffer: usize) -> Self {
+        self.buffer = buffer;
+        self
+    }
+
+    /// Starts polling in the background.
+    ///
+    /// Spoilers are only marked as seen once they're sent down the stream, and the cache is
+    /// committed after each site. Polling stops when [`Shutdown::shutdown`] is called or the
+    /// stream is dropped.
+    pub fn spawn(self) -> (Spoilers, Shutdown) {
+        let (tx, rx) = mpsc::channel(self.buffer);
+        let (stop, stopped) = oneshot::channel();
+        let handle = tokio::spawn(self.run(tx, stopped));
+        (
+            Spoilers { rx },
+            Shutdown {
+                stop,
+                handle: Some(handle),
+            },
+        )
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn run(
+        mut self,
+        mut tx: mpsc::Sender<Result<Resolved, Error>>,
+        mut stopped: oneshot::Receiver<()>,
+    ) -> Result<(), Error> {
+        'watch: loop {
+            for site in &self.sites {
+                let candidates = match site::candidates(site.as_ref(), &mut self.cache).await {
+                    Ok(candidates) => candidates,
+                    Err(e) => {
+                        tracing::warn!(site = site.name(), ?e, "failed to poll");
+                        if !send(&mut tx, &mut stopped, Err(e)).await {
+                            break 'watch;
+                        }
+                        continue;
+                    }
+                };
+                for candidate in candidates {
+                    let (resolved, known) = candidate.into_parts();
+                    if !send(&mut tx, &mut stopped, Ok(resolved)).await {
+                        break 'watch;
+                    }
+                    if let Err(e) = self.cache.remember(&[known]).await {
+                        tracing::warn!(?e, "failed to remember spoiler");
+                        if !send(&mut tx, &mut stopped, Err(e)).await {
+                            break 'watch;
+                        }
+                    }
+                }
+                // whatever failed to commit stays staged for the next poll to commit
+                if let Err(e) = self.cache.commit().await {
+                    tracing::warn!(?e, "failed to commit cache");
+                    if !send(&mut tx, &mut stopped, Err(e)).await {
+                        break 'watch;
+                    }
+                }
+            }
+            let delay = self.interval + jitter(self.jitter);
+            tracing::trace!(?delay, "waiting for next poll");
+            tokio::select! {
+                _ = &mut stopped => break,
+                _ = tokio::time::sleep(delay) => {}
+            }
+        }
+        tracing::trace!("committing cache");
+        self.cache.commit().await
+    }
+}
+
+/// Sends `item` down the stream, unless the watcher is stopped first, even if the stream is
+/// full and nobody's reading it. `false` if the watcher should stop.
+async fn send(
+    tx: &mut mpsc::Sender<Result<Resolved, Error>>,
+    stopped: &mut oneshot::Receiver<()>,
+    item: Result<Resolved, Error>,
+) -> bool {
+    tokio::select! {
+        _ = stopped => false,
+        sent = tx.send(item) => {
+            if sent.is_err() {
+                tracing::trace!("stream dropped");
+            }
+            sent.is_ok()
+        }
+    }
+}
+
+fn jitter(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    let random = RandomState::new().build_hasher().finish();
+    Duration::from_nanos(random % max.as_nanos().min(u64::MAX as u128) as u64)
+}
+
+/// The spoilers found by a [`Watcher`], or the errors it ran into while polling.
+pub struct Spoilers {
+    rx: mpsc::Receiver<Result<Resolved, Error>>,
+}
+
+impl Stream for Spoilers {
+    type Item = Result<Resolved, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
+
+/// Stops a [`Watcher`].
+pub struct Shutdown {
+    stop: oneshot::Sender<()>,
+    handle: Option<JoinHandle<Result<(), Error>>>,
+}
+
+impl Shutdown {
+    /// Stops polling once the current poll is done and waits for the cache to be committed.
+    pub async fn shutdown(mut self) -> Result<(), Error> {
+        let _ = self.stop.send(());
+        match self.handle.take().unwrap().await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use futures::StreamExt;
+    use reqwest::Url;
+
+    use super::*;
+    use crate::{
+        cache::{test::Images, Known},
+        mythic::Mythic,
+        transport::Fake,
+        Spoiler,
+    };
+
+    #[tokio::test]
+    async fn reports_each_spoiler_once_and_commits() {
+        let transport = Fake::new().with_page(
+            Url::parse("https://mythicspoiler.com/newspoilers.html").unwrap(),
+            r#"
+            <div class="grid-card"><a href="woe/cards/ragingfirebolt.html"><img src="woe/cards/ragingfirebolt.jpg"></a></div>
+            <div class="grid-card"><a href="woe/cards/gingerbreadhunter.html"><img src="woe/cards/gingerbreadhunter.jpg"></a></div>
+            "#,
+        );
+        let cache = Images::default();
+        let (mut spoilers, shutdown) = Watcher::new(cache.clone())
+            .site(Arc::new(Mythic::new(transport)))
+            .interval(Duration::from_millis(10))
+            .jitter(Duration::ZERO)
+            .spawn();
+
+        let first_two = spoilers
+            .by_ref()
+            .take(2)
+            .map(|s| s.unwrap().spoiler.source_site_url.to_string())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            first_two,
+            [
+                "https://mythicspoiler.com/woe/cards/gingerbreadhunter.html",
+                "https://mythicspoiler.com/woe/cards/ragingfirebolt.html",
+            ]
+        );
+        // give it a few more polls, which must not report the same cards again
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        shutdown.shutdown().await.unwrap();
+        assert_eq!(cache.committed(), 2);
+        assert_eq!(spoilers.count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn shuts_down_while_nobody_reads() {
+        let transport = Fake::new().with_page(
+            Url::parse("https://mythicspoiler.com/newspoilers.html").unwrap(),
+            r#"
+            <div class="grid-card"><a href="woe/cards/ragingfirebolt.html"><img src="woe/cards/ragingfirebolt.jpg"></a></div>
+            <div class="grid-card"><a href="woe/cards/gingerbreadhunter.html"><img src="woe/cards/gingerbreadhunter.jpg"></a></div>
+            <div class="grid-card"><a href="woe/cards/picklockprankster.html"><img src="woe/cards/picklockprankster.jpg"></a></div>
+            "#,
+        );
+        let cache = Images::default();
+        let (spoilers, shutdown) = Watcher::new(cache.clone())
+            .site(Arc::new(Mythic::new(transport)))
+            .buffer(1)
+            .spawn();
+        // enough for the stream to fill up
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        tokio::time::timeout(Duration::from_secs(5), shutdown.shutdown())
+            .await
+            .expect("shutdown hung")
+            .unwrap();
+        // nothing that didn't make it into the stream before it filled up is seen
+        let committed = cache.committed();
+        let sent = spoilers.count().await;
+        assert!(committed >= 1 && committed <= sent, "{committed} of {sent}");
+        assert!(sent < 3);
+    }
+
+    #[tokio::test]
+    async fn cache_errors_go_down_the_stream() {
+        /// Can't remember anything.
+        struct Amnesiac;
+
+        impl Cache for Amnesiac {
+            async fn filter_new(&mut self, spoilers: &[Spoiler]) -> Result<Vec<Spoiler>, Error> {
+                Ok(spoilers.to_vec())
+            }
+
+            async fn mark_seen(&mut self, _: &[Spoiler]) -> Result<(), Error> {
+                Ok(())
+            }
+
+            async fn forget(&mut self, _: &[Spoiler]) -> Result<(), Error> {
+                Ok(())
+            }
+
+            async fn remember(&mut self, _: &[Known]) -> Result<(), Error> {
+                Err(std::io::Error::other("disk full").into())
+            }
+        }
+
+        let transport = Fake::new().with_page(
+            Url::parse("https://mythicspoiler.com/newspoilers.html").unwrap(),
+            r#"<div class="grid-card"><a href="woe/cards/ragingfirebolt.html"><img src="woe/cards/ragingfirebolt.jpg"></a></div>"#,
+        );
+        let (spoilers, shutdown) = Watcher::new(Amnesiac)
+            .site(Arc::new(Mythic::new(transport)))
+            .interval(Duration::from_millis(10))
+            .jitter(Duration::ZERO)
+            .spawn();
+
+        // the card comes again on the next poll, since it was never remembered
+        let items = spoilers.take(4).collect::<Vec<_>>().await;
+        assert!(items[0].is_ok());
+        assert!(matches!(items[1], Err(Error::Io(_))));
+        assert!(items[2].is_ok());
+        assert!(matches!(items[3], Err(Error::Io(_))));
+        shutdown.shutdown().await.unwrap();
+    }
+
+    #[test]
+    fn jitter_is_bounded() {
+        assert_eq!(jitter(Duration::ZERO), Duration::ZERO);
+        for _ in 0..100 {
+            assert!(jitter(Duration::from_secs(1)) < Duration::from_secs(1));
+        }
+    }
+}